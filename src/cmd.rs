@@ -1,19 +1,27 @@
 use std::{
-    collections::{HashMap, HashSet},
-    env, fs,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
     path::PathBuf,
 };
 
 use clap::ArgMatches;
-use log::{info, warn};
+use log::{error, info, warn, LevelFilter};
 
 use yarner_lib::Document;
 
 use crate::{
-    code, compile,
-    config::Config,
-    files, lock, plugin, print,
+    code::{self, UnlabeledMode},
+    compile::{self, forward::Reference},
+    config::{Config, LintLevel},
+    files,
+    graph::{self, GraphFormat},
+    lint, lock, merge,
+    output::{Action, Output, OutputMode},
+    plugin::{self, PluginHost, PluginStage},
+    print,
+    review::{self, Review},
     util::{Fallible, JoinExt},
+    verify,
 };
 
 #[derive(PartialEq, Eq, Copy, Clone)]
@@ -27,14 +35,11 @@ pub fn run_with_args(
     matches: &ArgMatches,
     build_mode: Option<BuildMode>,
     strict: bool,
+    plugins: &mut PluginHost,
+    output: &mut Output,
 ) -> Fallible<(PathBuf, HashSet<PathBuf>, HashSet<PathBuf>)> {
     let config_path = matches.value_of("config").unwrap();
-    let mut config = Config::read(config_path)
-        .map_err(|err| format!("Could not read config file \"{}\": {}", config_path, err))?;
-
-    config
-        .check()
-        .map_err(|err| format!("Invalid config file \"{}\": {}", config_path, err))?;
+    let config = read_config(matches)?;
 
     let is_docs_rebuild = build_mode == Some(BuildMode::ForwardDocs);
 
@@ -53,53 +58,152 @@ pub fn run_with_args(
     }
 
     let lock_path = PathBuf::from(config_path).with_extension("lock");
-
-    let clean_code = matches.is_present("clean");
     let force = matches.is_present("force");
-    for lang in config.language.values_mut() {
-        lang.clean_code = clean_code;
+    let merge = matches.is_present("merge");
+    let keep_stale = matches.is_present("keep-stale");
+
+    let root_path = set_root(matches, &config)?;
+    let input_patterns = input_patterns(&config)?;
+
+    let write = output.mode() == OutputMode::Write;
+
+    // Snapshots of code generated with other settings are no base for reverse mode or merging
+    let config_hash = config.output_hash();
+    let config_changed = !is_docs_rebuild && lock::config_changed(&lock_path, &config_hash)?;
+    if reverse && config_changed {
+        return Err(
+            "The config has changed since the last build. Reverse mode not possible.\n  \
+            Revert the changes of the config, or re-create the code output with `yarner --force`."
+                .into(),
+        );
     }
 
-    let root = matches.value_of("root").or(config.paths.root.as_deref());
+    let mut needs_merge = false;
+    if !force
+        && output.mode() != OutputMode::Check
+        && !is_docs_rebuild
+        && config.paths.has_valid_code_path()
+    {
+        let changed = lock::files_changed(&lock_path, reverse)?;
+        if !changed.is_empty() {
+            if merge && config_changed {
+                warn!("The config has changed since the last build. Code changes can't be merged.");
+                output.protect(changed);
+            } else if merge {
+                needs_merge = true;
+            } else {
+                output.protect(changed);
+            }
+        }
+    }
 
-    let root_path = if let Some(path) = root {
-        env::set_current_dir(path)
-            .map_err(|err| format!("Unable to set root to \"{}\": {}", path, err))?;
-        PathBuf::from(path)
-    } else {
-        PathBuf::from(".")
+    let reverse_build = ReverseBuild {
+        snapshots: if reverse || needs_merge {
+            lock::snapshots(&lock_path)?
+        } else {
+            lock::Snapshots::default()
+        },
+        merge: needs_merge,
+        unlabeled: matches
+            .subcommand_matches("reverse")
+            .and_then(|sub_matches| sub_matches.value_of("unlabeled"))
+            .unwrap_or("report")
+            .parse()?,
+        review: review_mode(matches.subcommand_matches("reverse"))?,
     };
 
-    if let Some(dir) = matches.value_of("docs") {
-        config.paths.docs = Some(PathBuf::from(dir));
+    if !reverse && needs_merge {
+        info!("Merging code changes into Markdown sources");
+        process_inputs_reverse(input_patterns, &config, &reverse_build, output)?;
     }
-    if let Some(dir) = matches.value_of("code") {
-        config.paths.code = Some(PathBuf::from(dir));
+
+    let (source_files, code_files) = build(
+        input_patterns,
+        &config,
+        reverse.then_some(&reverse_build),
+        strict,
+        is_docs_rebuild,
+        plugins,
+        output,
+    )?;
+
+    if !output.protected().is_empty() {
+        warn!("{}", protected_message(reverse, output.protected()));
     }
-    if let Some(entry) = matches.value_of("entrypoint") {
-        config.paths.entrypoint = Some(entry.to_owned());
+
+    if !reverse && !is_docs_rebuild {
+        let kept = lock::remove_stale_files(&lock_path, &code_files, keep_stale, output)?;
+        output.keep(kept);
     }
-    if let Some(patterns) = matches.values_of("input") {
-        config.paths.files = Some(patterns.map(|pattern| pattern.to_owned()).collect());
+
+    if has_reverse_config && write && !is_docs_rebuild {
+        lock::write_lock(
+            lock_path,
+            &config_hash,
+            &source_files,
+            &code_files,
+            reverse,
+            output,
+        )?;
     }
 
-    let input_patterns = config.paths.files.as_ref().ok_or(
-        "No inputs provided via arguments or toml file. For help, use:\n\
-               > yarner -h",
-    )?;
+    Ok((
+        PathBuf::from(config_path),
+        source_files
+            .iter()
+            .map(|path| root_path.join(path))
+            .collect(),
+        code_files.iter().map(|path| root_path.join(path)).collect(),
+    ))
+}
 
-    if !force
-        && !is_docs_rebuild
-        && config.paths.has_valid_code_path()
-        && lock::files_changed(&lock_path, reverse)?
-    {
-        return Err(locked_error_message(reverse).into());
+/// Snapshots of the last build for reverse mode, and how to play back changes
+struct ReverseBuild {
+    snapshots: lock::Snapshots,
+    merge: bool,
+    unlabeled: UnlabeledMode,
+    review: Review,
+}
+
+fn review_mode(sub_matches: Option<&ArgMatches>) -> Fallible<Review> {
+    let sub_matches = match sub_matches {
+        Some(sub_matches) => sub_matches,
+        None => return Ok(Review::All),
+    };
+
+    if sub_matches.is_present("interactive") {
+        Ok(Review::Interactive)
+    } else if let Some(selection) = sub_matches.values_of("select") {
+        Ok(Review::Select(
+            selection.map(|sel| sel.parse()).collect::<Result<_, _>>()?,
+        ))
+    } else {
+        Ok(Review::All)
     }
+}
 
-    let (mut source_files, mut code_files) = if reverse {
-        process_inputs_reverse(input_patterns, &config)?
+/// Processes all inputs, and copies files to code and docs output.
+/// Runs in reverse mode if `reverse` is given.
+fn build(
+    input_patterns: &[String],
+    config: &Config,
+    reverse: Option<&ReverseBuild>,
+    strict: bool,
+    is_docs_rebuild: bool,
+    plugins: &mut PluginHost,
+    output: &mut Output,
+) -> Fallible<(HashSet<PathBuf>, HashSet<PathBuf>)> {
+    let (mut source_files, mut code_files) = if let Some(reverse) = reverse {
+        process_inputs_reverse(input_patterns, config, reverse, output)?
     } else {
-        process_inputs_forward(input_patterns, &config, strict, !is_docs_rebuild)?
+        process_inputs_forward(
+            input_patterns,
+            config,
+            strict,
+            !is_docs_rebuild,
+            plugins,
+            output,
+        )?
     };
 
     if !is_docs_rebuild {
@@ -110,14 +214,15 @@ pub fn run_with_args(
                 code_file_patterns,
                 config.paths.code_paths.as_deref(),
                 code_dir,
-                reverse,
+                reverse.is_some(),
+                output,
             )?;
             source_files.extend(copy_in);
             code_files.extend(copy_out);
         }
     }
 
-    if !reverse {
+    if reverse.is_none() {
         if let (Some(doc_dir), Some(doc_file_patterns)) =
             (&config.paths.docs, &config.paths.doc_files)
         {
@@ -126,38 +231,439 @@ pub fn run_with_args(
                 config.paths.doc_paths.as_deref(),
                 doc_dir,
                 false,
+                output,
             )?;
         }
     }
 
-    if has_reverse_config && !is_docs_rebuild {
-        lock::write_lock(lock_path, &source_files, &code_files)?;
+    Ok((source_files, code_files))
+}
+
+/// Reads and checks the config file, and applies overrides from command line arguments
+pub fn read_config(matches: &ArgMatches) -> Fallible<Config> {
+    let config_path = matches.value_of("config").unwrap();
+    let mut config = Config::read(config_path)
+        .map_err(|err| format!("Could not read config file \"{}\": {}", config_path, err))?;
+
+    config
+        .check()
+        .map_err(|err| format!("Invalid config file \"{}\": {}", config_path, err))?;
+
+    // `is_present` is also true for subcommand `clean`
+    let clean_code = matches.is_present("clean") && matches.subcommand_matches("clean").is_none();
+    for lang in config.language.values_mut() {
+        lang.clean_code = clean_code;
     }
 
-    Ok((
-        PathBuf::from(config_path),
-        source_files
+    if let Some(dir) = matches.value_of("docs") {
+        config.paths.docs = Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = matches.value_of("code") {
+        config.paths.code = Some(PathBuf::from(dir));
+    }
+    if let Some(entry) = matches.value_of("entrypoint") {
+        config.paths.entrypoint = Some(entry.to_owned());
+    }
+    if let Some(patterns) = matches.values_of("input") {
+        config.paths.files = Some(patterns.map(|pattern| pattern.to_owned()).collect());
+    }
+
+    Ok(config)
+}
+
+/// Changes the working directory to the project root, and returns the root path
+pub fn set_root(matches: &ArgMatches, config: &Config) -> Fallible<PathBuf> {
+    let root = matches.value_of("root").or(config.paths.root.as_deref());
+
+    if let Some(path) = root {
+        env::set_current_dir(path)
+            .map_err(|err| format!("Unable to set root to \"{}\": {}", path, err))?;
+        Ok(PathBuf::from(path))
+    } else {
+        Ok(PathBuf::from("."))
+    }
+}
+
+fn input_patterns(config: &Config) -> Fallible<&[String]> {
+    config.paths.files.as_deref().ok_or_else(|| {
+        "No inputs provided via arguments or toml file. For help, use:\n\
+               > yarner -h"
+            .into()
+    })
+}
+
+/// Checks the project for unreachable blocks, undefined macros and other problems
+pub fn run_lint(matches: &ArgMatches) -> Fallible {
+    let config = read_config(matches)?;
+    set_root(matches, &config)?;
+
+    let inputs = collect_documents(input_patterns(&config)?, &config)?;
+
+    let findings = lint::lint(&config, &inputs.documents);
+    let mut errors = 0;
+    for finding in &findings {
+        match finding.level {
+            LintLevel::Error => {
+                errors += 1;
+                error!("{}", finding);
+            }
+            LintLevel::Warn => warn!("{}", finding),
+            LintLevel::Allow => {}
+        }
+    }
+
+    if errors > 0 {
+        Err(format!("Lint failed with {} error(s).", errors).into())
+    } else {
+        info!("Lint finished with {} warning(s).", findings.len());
+        Ok(())
+    }
+}
+
+/// Prints the block dependency graph or the document graph to STDOUT
+pub fn run_graph(matches: &ArgMatches, sub_matches: &ArgMatches) -> Fallible {
+    let config = read_config(matches)?;
+    set_root(matches, &config)?;
+
+    let inputs = collect_documents(input_patterns(&config)?, &config)?;
+
+    let format = sub_matches
+        .value_of("format")
+        .unwrap()
+        .parse::<GraphFormat>()?;
+
+    let graph = if sub_matches.is_present("documents") {
+        graph::document_graph(&inputs.documents, &inputs.references)
+    } else {
+        graph::block_graph(&config, &inputs.documents)
+    };
+
+    println!("{}", graph.format(format)?);
+    Ok(())
+}
+
+/// Prints a single block with all macros expanded to STDOUT, without writing any files
+pub fn run_expand(matches: &ArgMatches, sub_matches: &ArgMatches) -> Fallible {
+    let mut config = read_config(matches)?;
+    set_root(matches, &config)?;
+
+    let labels = sub_matches.is_present("labels");
+    for lang in config.language.values_mut() {
+        lang.clean_code = !labels;
+    }
+
+    let name = sub_matches.value_of("block").unwrap();
+    let inputs = collect_documents(input_patterns(&config)?, &config)?;
+    // Code is extracted after pre-tangle plugins
+    let documents = if sub_matches.is_present("plugins") {
+        plugin::run_plugins(
+            &config,
+            inputs.documents,
+            &mut HashMap::new(),
+            PluginStage::PreTangle,
+            true,
+            &mut PluginHost::new(false),
+        )?
+    } else {
+        inputs.documents
+    };
+
+    let code = expand(&config, &documents, name, sub_matches.value_of("doc"))?;
+    print!("{}", code);
+
+    Ok(())
+}
+
+/// Expands the code block of the given name, optionally only searching in document `doc`
+fn expand(
+    config: &Config,
+    documents: &HashMap<PathBuf, Document>,
+    name: &str,
+    doc: Option<&str>,
+) -> Fallible<String> {
+    let mut candidates: Vec<_> = match doc {
+        Some(doc) => {
+            // Paths from the glob patterns may differ from the argument, like `./README.md`
+            let path = PathBuf::from(path_clean::clean(doc));
+            let document = documents
+                .iter()
+                .find(|(p, _)| path_clean::clean(&p.to_string_lossy()) == path)
+                .map(|(_, document)| document)
+                .ok_or_else(|| format!("Document not found: {}", doc))?;
+            vec![(path, document)]
+        }
+        None => documents
             .iter()
-            .map(|path| root_path.join(path))
+            .map(|(path, doc)| (path.clone(), doc))
             .collect(),
-        code_files.iter().map(|path| root_path.join(path)).collect(),
-    ))
+    };
+    candidates.retain(|(_, doc)| doc.code_blocks().any(|b| b.name.as_deref() == Some(name)));
+    candidates.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let (_, document) = match candidates.len() {
+        0 => return Err(format!("No code block named '{}' found", name).into()),
+        1 => candidates.pop().unwrap(),
+        _ => {
+            return Err(format!(
+                "Code block '{}' found in multiple documents: {}\n  \
+                Select one with option `--doc`",
+                name,
+                candidates
+                    .iter()
+                    .map(|(path, _)| path.display())
+                    .join(", ", '"')
+            )
+            .into())
+        }
+    };
+
+    let code_blocks = document.code_blocks_by_name();
+    let entry_blocks = &code_blocks[&Some(name)];
+
+    let first = entry_blocks[0];
+    let settings = first
+        .language
+        .as_deref()
+        .and_then(|lang| config.language.get(lang))
+        .or_else(|| {
+            first
+                .is_file
+                .then(|| PathBuf::from(name))
+                .as_ref()
+                .and_then(|path| path.extension())
+                .and_then(|ext| ext.to_str())
+                .and_then(|ext| config.language.get(ext))
+        });
+
+    print::code::print_code(&code_blocks, entry_blocks, settings, document.newline())
+}
+
+/// Checks the block labels in the code output for consistency with the Markdown sources
+pub fn run_verify_labels(matches: &ArgMatches) -> Fallible {
+    let config = read_config(matches)?;
+    set_root(matches, &config)?;
+
+    let inputs = compile_reverse(input_patterns(&config)?, &config)?;
+    check_labels(&inputs.code_files, &config, &inputs.documents)?;
+
+    info!("All block labels are valid.");
+    Ok(())
+}
+
+/// Runs a forward build in memory and compares all outputs with the files on disk
+pub fn run_check(matches: &ArgMatches) -> Fallible {
+    let level = log::max_level();
+    log::set_max_level(LevelFilter::Warn);
+
+    let mut output = Output::new(OutputMode::Check);
+    let result = run_with_args(
+        matches,
+        Some(BuildMode::Forward),
+        true,
+        &mut PluginHost::new(false),
+        &mut output,
+    );
+
+    log::set_max_level(level);
+    result?;
+
+    let mismatches = output.mismatches();
+    for mismatch in mismatches {
+        print!("{}", mismatch.diff);
+    }
+
+    if mismatches.is_empty() {
+        info!("All output files are up to date.");
+        Ok(())
+    } else {
+        Err(format!(
+            "{} output file(s) not up to date: {}\n  To update, run `yarner`",
+            mismatches.len(),
+            mismatches.iter().map(|m| m.path.display()).join(", ", '"')
+        )
+        .into())
+    }
+}
+
+/// Runs a forward or reverse build without writing any files, and prints the planned file operations
+pub fn run_dry_run(matches: &ArgMatches) -> Fallible {
+    let level = log::max_level();
+    log::set_max_level(LevelFilter::Warn);
+
+    let mut output = Output::new(OutputMode::DryRun);
+    let result = run_with_args(
+        matches,
+        None,
+        true,
+        &mut PluginHost::new(false),
+        &mut output,
+    );
+
+    log::set_max_level(level);
+    result?;
+
+    print!("{}", output.format_plan(matches.is_present("json"))?);
+    Ok(())
 }
 
-fn locked_error_message(is_reverse: bool) -> String {
-    if is_reverse {
-        r#"Markdown sources have changed. Stopping to prevent overwrite.
-  To run anyway, use `yarner --force reverse`"#
+/// Removes code and docs output files produced by Yarner
+pub fn run_clean(matches: &ArgMatches, sub_matches: &ArgMatches) -> Fallible {
+    let config_path = matches.value_of("config").unwrap();
+    let lock_path = PathBuf::from(config_path).with_extension("lock");
+    let config = read_config(matches)?;
+    set_root(matches, &config)?;
+
+    let force = matches.is_present("force");
+    let dry_run = matches.is_present("dry-run");
+    let all = !sub_matches.is_present("code") && !sub_matches.is_present("docs");
+
+    let mut dirs = vec![];
+    if all || sub_matches.is_present("code") {
+        dirs.extend(config.paths.code.clone());
+    }
+    if all || sub_matches.is_present("docs") {
+        dirs.extend(config.paths.docs.clone());
+    }
+
+    // Code files recorded in the lock file count as modified if they differ from their generated content
+    let mut produced: BTreeMap<PathBuf, bool> = lock::locked_code_files(&lock_path)?
+        .into_iter()
+        .map(|file| (file.path, file.modified))
+        .collect();
+
+    // Other files, like documentation output, are compared with a rebuild
+    let clean_docs = all || sub_matches.is_present("docs");
+    let copies_code = config.paths.code_files.is_some();
+    let level = log::max_level();
+    if clean_docs || copies_code || produced.is_empty() {
+        log::set_max_level(LevelFilter::Warn);
+        let mut planned = Output::new(OutputMode::DryRun);
+        let result = build(
+            input_patterns(&config)?,
+            &config,
+            None,
+            false,
+            false,
+            &mut PluginHost::new(false),
+            &mut planned,
+        );
+        log::set_max_level(level);
+
+        match result {
+            Ok(_) => {
+                for file in planned.plan().iter().filter(|file| file.path.is_file()) {
+                    produced
+                        .entry(file.path.clone())
+                        .or_insert(file.action != Action::Skip);
+                }
+            }
+            Err(err) => warn!(
+                "Unable to build the project, only removing code files recorded in the lock file: {}",
+                err
+            ),
+        }
+    }
+
+    let mut output = Output::new(if dry_run {
+        OutputMode::DryRun
     } else {
-        r#"Code output has changed. Stopping to prevent overwrite.
-  To run anyway, use `yarner --force`"#
+        OutputMode::Write
+    });
+    if dry_run {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    let mut removed = vec![];
+    for (path, modified) in produced {
+        if !dirs.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+        if modified && !force {
+            warn!(
+                "Not removing file {}, as it was modified since it was generated.\n  To remove anyway, use `yarner --force clean`",
+                path.display()
+            );
+            continue;
+        }
+        info!("Removing file {}", path.display());
+        output.remove(&path)?;
+        removed.push(path);
     }
-    .to_string()
+
+    if dry_run {
+        log::set_max_level(level);
+        print!("{}", output.format_plan(matches.is_present("json"))?);
+    } else {
+        files::remove_empty_dirs(&removed, &dirs)?;
+        lock::forget_code_files(&lock_path, &removed)?;
+    }
+
+    Ok(())
+}
+
+/// Extends the protection of changed files to the files depending on them.
+/// In forward mode, documents producing a protected code file are protected, with all their output.
+/// In reverse mode, the code files produced by protected documents are protected.
+fn protect_dependents(
+    config: &Config,
+    documents: &HashMap<PathBuf, Document>,
+    is_reverse: bool,
+    output: &mut Output,
+) {
+    if output.protected().is_empty() {
+        return;
+    }
+
+    let mut dependents = vec![];
+    for (path, document) in documents {
+        let code_files = compile::forward::code_files(config, document, path);
+        if is_reverse {
+            if output.protected().contains(path) {
+                dependents.extend(code_files);
+            }
+        } else if code_files
+            .iter()
+            .any(|file| output.protected().contains(file))
+        {
+            dependents.push(path.clone());
+            dependents.extend(compile::forward::docs_file(config, path));
+            dependents.extend(code_files);
+        }
+    }
+    output.protect(dependents);
+}
+
+/// Lists the files skipped due to changes since the last build
+fn protected_message(is_reverse: bool, protected: &HashSet<PathBuf>) -> String {
+    let mut files: Vec<_> = protected.iter().collect();
+    files.sort();
+
+    format!(
+        "{} since the last build. Skipped {} file(s) to prevent overwrite:\n{}\n  {}",
+        if is_reverse {
+            "Markdown sources have changed"
+        } else {
+            "Code output has changed"
+        },
+        files.len(),
+        files
+            .iter()
+            .map(|file| format!("    {}", file.display()))
+            .join("\n", ""),
+        if is_reverse {
+            "To merge the changes, use `yarner --merge reverse`\n  \
+            To overwrite anyway, use `yarner --force reverse`"
+        } else {
+            "To merge the changes, use `yarner --merge`\n  \
+            To overwrite anyway, use `yarner --force`"
+        }
+    )
 }
 
 fn process_inputs_reverse(
     input_patterns: &[String],
     config: &Config,
+    reverse: &ReverseBuild,
+    output: &mut Output,
 ) -> Fallible<(HashSet<PathBuf>, HashSet<PathBuf>)> {
     let code_dir = config.paths.code.as_ref().ok_or({
         r#"Missing code output location. Reverse mode not possible.
@@ -180,6 +686,120 @@ fn process_inputs_reverse(
         .into());
     }
 
+    let ReverseInputs {
+        documents,
+        source_files,
+        code_files,
+    } = compile_reverse(input_patterns, config)?;
+    protect_dependents(config, &documents, true, output);
+
+    check_labels(&code_files, config, &documents)?;
+
+    // Files tangled with a source map have no block labels, and are played back from the source map
+    let labeled_files: HashSet<_> = code_files
+        .iter()
+        .filter(|file| !reverse.snapshots.source_maps.contains_key(*file))
+        .cloned()
+        .collect();
+    for file in &labeled_files {
+        let language = file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| config.language.get(ext));
+        if language.is_some_and(|language| language.has_labels()) {
+            output.record_labeled(file);
+        }
+    }
+    let (mut occurrences, unlabeled) = code::collect_code_blocks(&labeled_files, config)?;
+    let source_maps =
+        code::collect_mapped_code_blocks(&reverse.snapshots.source_maps, config, &mut occurrences)?;
+    for (path, source_map) in source_maps {
+        output.record_source_map(&path, source_map);
+    }
+
+    let base_blocks = collect_base_blocks(&code_files, config, reverse)?;
+    let mut code_blocks = code::resolve_occurrences(occurrences, base_blocks.as_ref())?;
+
+    let mut conflicts = 0;
+    if reverse.merge {
+        if let Some(base_blocks) = &base_blocks {
+            conflicts =
+                merge::merge_blocks(&documents, &config.parser, base_blocks, &mut code_blocks);
+        } else {
+            warn!("No snapshots of the last build found. Code changes take precedence.");
+        }
+    }
+
+    let added_blocks = code::resolve_unlabeled(
+        unlabeled,
+        reverse.unlabeled,
+        &config.parser,
+        &mut code_blocks,
+    );
+
+    let skipped = review::review_changes(
+        &reverse.review,
+        &documents,
+        &config.parser,
+        &mut code_blocks,
+    )?;
+
+    // Code files with skipped changes keep their lock entries and snapshots,
+    // so that the next forward build does not overwrite the changes
+    output.keep(skipped.values().map(|block| block.code_file.clone()));
+
+    for (path, doc) in documents {
+        let added: Vec<_> = added_blocks
+            .iter()
+            .filter(|added| {
+                added.file == path
+                    && !skipped.contains_key(&(path.clone(), added.after.0.clone(), added.after.1))
+            })
+            .collect();
+        let blocks: HashMap<_, _> = code_blocks
+            .iter()
+            .filter_map(|((p, name, index), block)| {
+                if p == &path {
+                    Some(((name, index), block))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if !blocks.is_empty() {
+            let print = print::docs::print_reverse(&doc, &config.parser, &blocks, &added);
+            if output.write(&path, &print)? {
+                info!("  Writing back to file {}", path.display());
+            } else {
+                info!("  Skipping unchanged file {}", path.display());
+            }
+        } else {
+            info!("  Skipping file {}", path.display());
+        }
+    }
+
+    // Code generated from conflict markers is useless, so stop before tangling and updating the lock
+    if conflicts > 0 {
+        return Err(format!(
+            "Merge finished with {} conflict(s). Stopping.\n  \
+            Resolve the conflict markers in the Markdown sources, and run Yarner again.",
+            conflicts
+        )
+        .into());
+    }
+
+    Ok((source_files, code_files))
+}
+
+/// Documents compiled for reverse mode, with the source files and code files involved
+struct ReverseInputs {
+    documents: HashMap<PathBuf, Document>,
+    source_files: HashSet<PathBuf>,
+    code_files: HashSet<PathBuf>,
+}
+
+fn compile_reverse(input_patterns: &[String], config: &Config) -> Fallible<ReverseInputs> {
     let mut any_input = false;
 
     let mut documents: HashMap<PathBuf, Document> = HashMap::new();
@@ -227,44 +847,91 @@ fn process_inputs_reverse(
         .into());
     }
 
-    let code_blocks = code::collect_code_blocks(&code_files, config)?;
-    for (path, doc) in documents {
-        let blocks: HashMap<_, _> = code_blocks
-            .iter()
-            .filter_map(|((p, name, index), block)| {
-                if p == &path {
-                    Some(((name, index), block))
-                } else {
-                    None
-                }
-            })
-            .collect();
+    Ok(ReverseInputs {
+        documents,
+        source_files,
+        code_files,
+    })
+}
 
-        if !blocks.is_empty() {
-            let print = print::docs::print_reverse(&doc, &config.parser, &blocks);
-            if files::file_differs(&path, &print) {
-                info!("  Writing back to file {}", path.display());
-                fs::write(&path, print)?;
-            } else {
-                info!("  Skipping unchanged file {}", path.display());
+/// Verifies the block labels in the code output, and fails with all errors found
+fn check_labels(
+    code_files: &HashSet<PathBuf>,
+    config: &Config,
+    documents: &HashMap<PathBuf, Document>,
+) -> Fallible {
+    let errors = verify::verify_labels(code_files, config, documents)?;
+    for err in &errors {
+        error!("{}", err);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Found {} invalid block label(s) in code output.\n  \
+            Fix the labels, or re-create the code output with `yarner --force`.",
+            errors.len()
+        )
+        .into())
+    }
+}
+
+/// Collects the code blocks of the last build from the snapshot store, if available
+fn collect_base_blocks(
+    code_files: &HashSet<PathBuf>,
+    config: &Config,
+    reverse: &ReverseBuild,
+) -> Fallible<Option<HashMap<code::BlockKey, code::RevCodeBlock>>> {
+    let snapshots = &reverse.snapshots;
+    if snapshots.code.is_empty() && snapshots.source_maps.is_empty() {
+        return Ok(None);
+    }
+
+    let (mut base_blocks, _) = code::collect_code_blocks_with(code_files, config, |file| {
+        match snapshots.code.get(file) {
+            Some(snapshot) => files::read_file_string(snapshot).map(Some),
+            None => {
+                if reverse.merge {
+                    warn!(
+                        "No snapshot of code file {} found. Code changes take precedence.",
+                        file.display()
+                    );
+                }
+                Ok(None)
             }
-        } else {
-            info!("  Skipping file {}", path.display());
         }
+    })?;
+    for (file, source_map) in &snapshots.source_maps {
+        code::insert_source_map_blocks(
+            &files::read_file_string(source_map)?,
+            file,
+            config,
+            &mut base_blocks,
+        )?;
     }
 
-    Ok((source_files, code_files))
+    // Occurrences of generated blocks are all equal
+    Ok(Some(
+        base_blocks
+            .into_iter()
+            .map(|(key, mut blocks)| (key, blocks.swap_remove(0)))
+            .collect(),
+    ))
 }
 
-fn process_inputs_forward(
-    input_patterns: &[String],
-    config: &Config,
-    strict: bool,
-    write_code: bool,
-) -> Fallible<(HashSet<PathBuf>, HashSet<PathBuf>)> {
+/// Documents collected from the input files, following links and transclusions
+struct Inputs {
+    documents: HashMap<PathBuf, Document>,
+    source_files: HashSet<PathBuf>,
+    references: Vec<Reference>,
+}
+
+fn collect_documents(input_patterns: &[String], config: &Config) -> Fallible<Inputs> {
     let mut any_input = false;
     let mut documents = HashMap::new();
     let mut source_file = HashSet::new();
+    let mut references = vec![];
     for pattern in input_patterns {
         let paths = glob::glob(pattern)
             .map_err(|err| format!("Unable to process glob pattern \"{}\": {}", pattern, err))?;
@@ -283,6 +950,7 @@ fn process_inputs_forward(
                     &file_name,
                     &mut documents,
                     &mut source_file,
+                    &mut references,
                 )
                 .map_err(|err| {
                     format!(
@@ -305,14 +973,358 @@ fn process_inputs_forward(
         .into());
     }
 
+    Ok(Inputs {
+        documents,
+        source_files: source_file,
+        references,
+    })
+}
+
+fn process_inputs_forward(
+    input_patterns: &[String],
+    config: &Config,
+    strict: bool,
+    write_code: bool,
+    plugins: &mut PluginHost,
+    output: &mut Output,
+) -> Fallible<(HashSet<PathBuf>, HashSet<PathBuf>)> {
+    let Inputs {
+        documents,
+        source_files,
+        ..
+    } = collect_documents(input_patterns, config)?;
+    protect_dependents(config, &documents, false, output);
+
+    let mut extra_files = HashMap::new();
+    let documents = plugin::run_plugins(
+        config,
+        documents,
+        &mut extra_files,
+        PluginStage::PreTangle,
+        strict,
+        plugins,
+    )?;
+
     let code_files = if write_code {
-        compile::forward::extract_code_all(config, &documents)?
+        compile::forward::extract_code_all(config, &documents, output)?
     } else {
         HashMap::new()
     };
+    // Post-tangle plugins affect the documentation, so they run for docs-only rebuilds as well
+    let documents = plugin::run_plugins(
+        config,
+        documents,
+        &mut extra_files,
+        PluginStage::PostTangle,
+        strict,
+        plugins,
+    )?;
+
+    let documents = plugin::run_plugins(
+        config,
+        documents,
+        &mut extra_files,
+        PluginStage::Docs,
+        strict,
+        plugins,
+    )?;
+    compile::forward::write_documentation_all(config, &documents, output)?;
+
+    let extra_code_files = compile::forward::write_extra_files(
+        config,
+        &extra_files,
+        &documents,
+        &code_files,
+        write_code,
+        output,
+    )?;
+
+    Ok((
+        source_files,
+        code_files.into_keys().chain(extra_code_files).collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use crate::testing::{Project, CONFIG};
+    use std::fs;
+
+    const DOC_A: &str = r#"# A
+
+```rust
+//- Main
+fn main() {
+    // ==> Body.
+}
+```
+
+```rust
+//- Body
+run();
+```
+
+```rust
+//- Shared
+a();
+```
+"#;
+
+    const DOC_B: &str = r#"# B
+
+```rust
+//- Shared
+b();
+```
+"#;
+
+    fn documents(config: &Config) -> HashMap<PathBuf, Document> {
+        [("a.md", DOC_A), ("docs/b.md", DOC_B)]
+            .iter()
+            .map(|(path, text)| {
+                let path = PathBuf::from(path);
+                let (doc, _) = parse::parse(text, &path, &path, false, &config.parser).unwrap();
+                (path, doc)
+            })
+            .collect()
+    }
 
-    let documents = plugin::run_plugins(config, documents, strict)?;
-    compile::forward::write_documentation_all(config, &documents)?;
+    #[test]
+    fn expand_block() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let documents = documents(&config);
 
-    Ok((source_file, code_files.keys().cloned().collect()))
+        let code = expand(&config, &documents, "Main", None).unwrap();
+        assert_eq!(code, "fn main() {\n    run();\n}\n");
+
+        let code = expand(&config, &documents, "Shared", Some("./docs/b.md")).unwrap();
+        assert_eq!(code, "b();\n");
+    }
+
+    #[test]
+    fn expand_errors() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let documents = documents(&config);
+
+        let err = expand(&config, &documents, "Missing", None).unwrap_err();
+        assert_eq!(err.to_string(), "No code block named 'Missing' found");
+
+        let err = expand(&config, &documents, "Shared", None).unwrap_err();
+        assert!(err.to_string().starts_with(
+            "Code block 'Shared' found in multiple documents: \"a.md\", \"docs/b.md\""
+        ));
+
+        let err = expand(&config, &documents, "Main", Some("docs/b.md")).unwrap_err();
+        assert_eq!(err.to_string(), "No code block named 'Main' found");
+
+        let err = expand(&config, &documents, "Main", Some("c.md")).unwrap_err();
+        assert_eq!(err.to_string(), "Document not found: c.md");
+    }
+
+    const CLEAN_README: &str = r#"# Clean
+
+```rust
+//- file:a.rs
+fn a() {}
+```
+
+```rust
+//- file:b.rs
+fn b() {}
+```
+"#;
+
+    #[test]
+    fn clean_after_source_edit() {
+        let project = Project::new("clean-source-edit", CLEAN_README);
+        project.yarner_ok(&[]);
+
+        project.write(
+            "README.md",
+            &CLEAN_README.replace("fn a() {}", "fn a() { edited(); }"),
+        );
+        project.yarner_ok(&["clean", "--code"]);
+        assert!(!project.exists("code/a.rs"));
+        assert!(!project.exists("code/b.rs"));
+    }
+
+    #[test]
+    fn clean_after_output_edit() {
+        let project = Project::new("clean-output-edit", CLEAN_README);
+        project.yarner_ok(&[]);
+
+        project.write("code/a.rs", "fn a() { edited(); }\n");
+        project.yarner_ok(&["clean"]);
+        assert!(project.exists("code/a.rs"));
+        assert!(!project.exists("code/b.rs"));
+        assert!(!project.exists("docs/README.md"));
+
+        project.yarner_ok(&["--force", "clean"]);
+        assert!(!project.exists("code/a.rs"));
+    }
+
+    #[test]
+    fn clean_broken_sources() {
+        let project = Project::new("clean-broken", CLEAN_README);
+        project.yarner_ok(&[]);
+
+        let circular = "# Clean\n\n```rust\n//- file:a.rs\n// ==> Loop.\n```\n\n```rust\n//- Loop\n// ==> Loop.\n```\n";
+        project.write("README.md", circular);
+        project.yarner_ok(&["clean", "--code"]);
+        assert!(!project.exists("code/a.rs"));
+        assert!(!project.exists("code/b.rs"));
+    }
+
+    fn snapshots(project: &Project) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(project.path("Yarner.snapshots"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn snapshots_of_labeled_code_only() {
+        let project = Project::new(
+            "snapshots-labeled",
+            "# Snapshots\n\n```rust\n//- file:a.rs\nfn a() {}\n```\n",
+        );
+        project.write(
+            "Yarner.toml",
+            &CONFIG.replace(
+                "# code_files = [\"**/*.rs\"]",
+                "code_files = [\"assets/*.rs\"]",
+            ),
+        );
+        project.write("assets/b.rs", "fn b() {}\n");
+        project.yarner_ok(&[]);
+        assert!(project.exists("code/assets/b.rs"));
+        assert_eq!(
+            snapshots(&project),
+            vec![lock::hash_file(project.path("code/a.rs")).unwrap()]
+        );
+
+        project.write(
+            "code/a.rs",
+            "// <@README.md#a.rs#0\nfn a() { edited(); }\n// @>README.md#a.rs#0\n",
+        );
+        project.yarner_ok(&["reverse"]);
+        assert_eq!(
+            snapshots(&project),
+            vec![lock::hash_file(project.path("code/a.rs")).unwrap()]
+        );
+    }
+
+    const MERGE_README: &str = r#"# Merge
+
+```rust
+//- file:a.rs
+fn a() {
+    base();
+}
+```
+"#;
+
+    #[test]
+    fn merge_conflict_stops_build() {
+        let project = Project::new("merge-conflict", MERGE_README);
+        project.yarner_ok(&[]);
+        let lock = project.read("Yarner.lock");
+        let snapshots_before = snapshots(&project);
+
+        let code_edited =
+            "// <@README.md#a.rs#0\nfn a() {\n    code();\n}\n// @>README.md#a.rs#0\n";
+        project.write("README.md", &MERGE_README.replace("base", "source"));
+        project.write("code/a.rs", code_edited);
+        let (result, _) = project.yarner(&["--merge"]);
+        assert!(result.is_err());
+
+        let readme = project.read("README.md");
+        assert!(readme.contains("<<<<<<< source"));
+        assert!(readme.contains("    source();"));
+        assert!(readme.contains("    code();"));
+
+        assert_eq!(project.read("code/a.rs"), code_edited);
+        assert_eq!(project.read("Yarner.lock"), lock);
+        assert_eq!(snapshots(&project), snapshots_before);
+    }
+
+    #[test]
+    fn skipped_changes_survive_forward_build() {
+        let project = Project::new(
+            "review-skip",
+            "# Review\n\n```rust\n//- file:a.rs\nfn a() {}\n```\n\n```rust\n//- file:b.rs\nfn b() {}\n```\n",
+        );
+        project.yarner_ok(&[]);
+
+        let code_a = "// <@README.md#a.rs#0\nfn a() { edited(); }\n// @>README.md#a.rs#0\n";
+        let code_b = "// <@README.md#b.rs#0\nfn b() { edited(); }\n// @>README.md#b.rs#0\n";
+        project.write("code/a.rs", code_a);
+        project.write("code/b.rs", code_b);
+        project.yarner_ok(&["reverse", "--select", "README.md#b.rs"]);
+
+        let readme = project.read("README.md");
+        assert!(readme.contains("fn a() {}"));
+        assert!(readme.contains("fn b() { edited(); }"));
+
+        let log = project.yarner_ok(&[]);
+        assert!(log.contains("Code output has changed"));
+        assert_eq!(project.read("code/a.rs"), code_a);
+        assert_eq!(project.read("code/b.rs"), code_b);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_tangle_plugins_on_docs_rebuild() {
+        let project = Project::new(
+            "docs-rebuild",
+            "# Title\n\n```rust\n//- file:a.rs\nfn a() {}\n```\n",
+        );
+        project.write(
+            "Yarner.toml",
+            &format!(
+                "{}\n[plugin.rename]\nstage = \"post-tangle\"\ncommand = \"sh\"\narguments = [\"-c\", \"sed 's/# Title/# Renamed/'\"]\n",
+                CONFIG
+            ),
+        );
+
+        let matches = crate::app().get_matches_from(["yarner"]);
+        let (result, _) = project.run(|| {
+            run_with_args(
+                &matches,
+                Some(BuildMode::ForwardDocs),
+                true,
+                &mut PluginHost::new(false),
+                &mut Output::new(OutputMode::Write),
+            )
+        });
+        result.unwrap();
+        assert!(project.read("docs/README.md").starts_with("# Renamed"));
+        assert!(!project.exists("code/a.rs"));
+    }
+
+    #[test]
+    fn reverse_clean_code_with_source_map() {
+        let project = Project::new(
+            "source-map-clean",
+            "# Source map\n\n```rust\n//- file:a.rs\nfn a() {}\n```\n",
+        );
+        project.write(
+            "Yarner.toml",
+            &CONFIG.replace(
+                "eof_newline = true",
+                "eof_newline = true\nsource_map = true",
+            ),
+        );
+        project.yarner_ok(&["--clean"]);
+        assert_eq!(project.read("code/a.rs"), "fn a() {}\n");
+
+        project.write("code/a.rs", "fn a() { edited(); }\n");
+        let log = project.yarner_ok(&["reverse"]);
+        assert!(!log.contains("outside of block labels"), "{}", log);
+        assert!(project.read("README.md").contains("fn a() { edited(); }"));
+    }
 }
@@ -1,14 +1,18 @@
 use crate::config::{BlockLabels, Config, ParserSettings};
 use crate::files;
 use crate::util::Fallible;
-use log::warn;
-use std::collections::{
-    hash_map::Entry::{Occupied, Vacant},
-    HashMap, HashSet,
-};
-use std::path::PathBuf;
+use crate::util::JoinExt;
+use log::{info, warn};
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-type BlockKey = (PathBuf, Option<String>, usize);
+/// Identifies a code block by source file, block name and block index
+pub type BlockKey = (PathBuf, Option<String>, usize);
+
+/// All occurrences of code blocks in the code output, by block
+pub type Occurrences = HashMap<BlockKey, Vec<RevCodeBlock>>;
 
 /// Representation of a code block
 pub struct RevCodeBlock {
@@ -22,29 +26,108 @@ pub struct RevCodeBlock {
     pub lines: Vec<String>,
     /// Indent
     pub indent: String,
+    /// Code file the block occurs in
+    pub code_file: PathBuf,
+    /// Line number of the block's first line in the code file
+    pub line: usize,
 }
 
 impl RevCodeBlock {
-    fn new(file: String, name: Option<String>, index: usize, indent: String) -> Self {
+    fn new(file: String, name: Option<String>, index: usize, indent: String, line: usize) -> Self {
         Self {
             file,
             name,
             index,
             lines: vec![],
             indent,
+            code_file: PathBuf::new(),
+            line,
         }
     }
+
+    /// Location of the block in the code output, as `file:line`
+    fn location(&self) -> String {
+        format!("{}:{}", self.code_file.display(), self.line)
+    }
     fn push_line(&mut self, line: String) {
         self.lines.push(line);
     }
 }
 
-/// Collects all code blocks from the given code files
+/// Lines of a code file outside of any block labels
+#[derive(Debug)]
+pub struct Unlabeled {
+    /// Code file
+    pub code_file: PathBuf,
+    /// Line number of the first line
+    pub line: usize,
+    /// Text lines
+    pub lines: Vec<String>,
+    /// Top-level block preceding the lines
+    pub preceding: Option<BlockKey>,
+    /// Top-level block following the lines
+    pub following: Option<BlockKey>,
+}
+
+/// How to handle lines outside of block labels in reverse mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnlabeledMode {
+    /// Report the lines, without playing them back
+    Report,
+    /// Attach the lines to the adjacent top-level block
+    Attach,
+    /// Insert the lines as a new block, and a macro invocation into the adjacent top-level block
+    Block,
+}
+
+impl FromStr for UnlabeledMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(UnlabeledMode::Report),
+            "attach" => Ok(UnlabeledMode::Attach),
+            "block" => Ok(UnlabeledMode::Block),
+            _ => Err(format!("Unknown mode for unlabeled lines '{}'", s)),
+        }
+    }
+}
+
+/// A new code block, to be inserted into a document after an existing block
+#[derive(Debug)]
+pub struct AddedBlock {
+    /// Doc source file
+    pub file: PathBuf,
+    /// Name and index of the block to insert after
+    pub after: (Option<String>, usize),
+    /// Block name
+    pub name: String,
+    /// Text lines
+    pub lines: Vec<String>,
+}
+
+/// Collects all occurrences of code blocks from the given code files, as well as lines outside of block labels
 pub fn collect_code_blocks(
     code_files: &HashSet<PathBuf>,
     config: &Config,
-) -> Fallible<HashMap<BlockKey, RevCodeBlock>> {
-    let mut code_blocks: HashMap<BlockKey, RevCodeBlock> = HashMap::new();
+) -> Fallible<(Occurrences, Vec<Unlabeled>)> {
+    collect_code_blocks_with(code_files, config, |file| {
+        files::read_file_string(file).map(Some)
+    })
+}
+
+/// Collects all occurrences of code blocks from the given code files, with file content provided by `read`.
+/// Files for which `read` returns `None` are skipped.
+pub fn collect_code_blocks_with<F>(
+    code_files: &HashSet<PathBuf>,
+    config: &Config,
+    read: F,
+) -> Fallible<(Occurrences, Vec<Unlabeled>)>
+where
+    F: Fn(&Path) -> Fallible<Option<String>>,
+{
+    let mut code_blocks = Occurrences::new();
+    let mut unlabeled = vec![];
 
     if !config.language.is_empty() {
         for file in code_files {
@@ -55,124 +138,481 @@ pub fn collect_code_blocks(
                     .get(language)
                     .and_then(|lang| lang.block_labels.as_ref())
                 {
-                    let source = files::read_file_string(file)?;
-                    let blocks = parse(&source, &config.parser, labels)?;
-
-                    for block in blocks.into_iter() {
-                        let path = PathBuf::from(&block.file);
-                        match code_blocks.entry((path, block.name.clone(), block.index)) {
-                            Occupied(entry) => {
-                                if entry.get().lines != block.lines {
-                                    return Err(format!("Reverse mode impossible due to multiple, differing occurrences of a code block: {} # {} # {}", &block.file, &block.name.unwrap_or_else(|| "".to_string()), block.index).into());
-                                } else {
-                                    warn!(
-                                        "Multiple occurrences of a code block: {} # {} # {}",
-                                        &block.file,
-                                        &block.name.unwrap_or_else(|| "".to_string()),
-                                        block.index
-                                    )
-                                }
-                            }
-                            Vacant(entry) => {
-                                entry.insert(block);
-                            }
-                        }
-                    }
+                    let source = match read(file)? {
+                        Some(source) => source,
+                        None => continue,
+                    };
+                    let (blocks, lines) = parse(&source, &config.parser, labels)?;
+                    insert_blocks(&mut code_blocks, blocks, file);
+                    unlabeled.extend(lines.into_iter().map(|lines| Unlabeled {
+                        code_file: file.clone(),
+                        ..lines
+                    }));
                 }
             }
         }
     }
 
-    Ok(code_blocks)
+    Ok((code_blocks, unlabeled))
 }
 
-fn parse(
-    source: &str,
+/// Handles lines outside of block labels. Returns new blocks to insert into documents.
+pub fn resolve_unlabeled(
+    unlabeled: Vec<Unlabeled>,
+    mode: UnlabeledMode,
     parser: &ParserSettings,
-    block_labels: &BlockLabels,
-) -> Fallible<Vec<RevCodeBlock>> {
+    code_blocks: &mut HashMap<BlockKey, RevCodeBlock>,
+) -> Vec<AddedBlock> {
+    let mut added = vec![];
+    for lines in unlabeled {
+        let target = match (&lines.preceding, &lines.following) {
+            (Some(key), _) => Some((key, true)),
+            (None, Some(key)) => Some((key, false)),
+            (None, None) => None,
+        };
+        let target = target.and_then(|(key, append)| {
+            code_blocks
+                .get_mut(key)
+                .map(|block| (key.clone(), append, block))
+        });
+
+        let (key, append, block) = match (mode, target) {
+            (UnlabeledMode::Attach | UnlabeledMode::Block, Some(target)) => target,
+            _ => {
+                warn!(
+                    "{} line(s) outside of block labels in {}:{} are not played back.\n  \
+                     To keep them, use `yarner reverse --unlabeled attach` or `--unlabeled block`",
+                    lines.lines.len(),
+                    lines.code_file.display(),
+                    lines.line
+                );
+                continue;
+            }
+        };
+
+        let new_lines = if mode == UnlabeledMode::Attach {
+            lines.lines
+        } else {
+            let name = format!(
+                "Unlabeled {}:{}",
+                lines.code_file.to_string_lossy().replace('\\', "/"),
+                lines.line
+            );
+            added.push(AddedBlock {
+                file: key.0.clone(),
+                after: (key.1.clone(), key.2),
+                name: name.clone(),
+                lines: lines.lines,
+            });
+            vec![format!(
+                "{}{}{}{}",
+                parser.macro_start,
+                if parser.macro_start.ends_with(' ') {
+                    ""
+                } else {
+                    " "
+                },
+                name,
+                parser.macro_end,
+            )]
+        };
+
+        if append {
+            block.lines.extend(new_lines);
+        } else {
+            block.lines.splice(0..0, new_lines);
+        }
+    }
+    added
+}
+
+/// Start of block labels in source maps. Not expected in any code.
+const SOURCE_MAP_COMMENT: &str = "\u{1e}";
+
+/// Block labels of source maps, recorded for code output without labels
+pub fn source_map_labels() -> BlockLabels {
+    BlockLabels {
+        comment_start: SOURCE_MAP_COMMENT.to_string(),
+        comment_end: None,
+        block_start: "<@".to_string(),
+        block_next: "<@>".to_string(),
+        block_end: "@>".to_string(),
+    }
+}
+
+/// Collects code blocks from code files without labels, using the source maps of the last generated code.
+/// Returns the source maps updated to the current content of the code files.
+pub fn collect_mapped_code_blocks(
+    source_maps: &HashMap<PathBuf, PathBuf>,
+    config: &Config,
+    code_blocks: &mut Occurrences,
+) -> Fallible<HashMap<PathBuf, String>> {
+    let mut updated = HashMap::new();
+    for (file, source_map_file) in source_maps {
+        if !file.is_file() {
+            continue;
+        }
+        let source_map = update_source_map(
+            &files::read_file_string(source_map_file)?,
+            &files::read_file_string(file)?,
+        );
+        insert_source_map_blocks(&source_map, file, config, code_blocks)?;
+        updated.insert(file.clone(), source_map);
+    }
+    Ok(updated)
+}
+
+/// Collects code blocks from the source map of a code file
+pub fn insert_source_map_blocks(
+    source_map: &str,
+    code_file: &Path,
+    config: &Config,
+    code_blocks: &mut Occurrences,
+) -> Fallible {
+    let (blocks, _) = parse(source_map, &config.parser, &source_map_labels())?;
+    insert_blocks(code_blocks, blocks, code_file);
+    Ok(())
+}
+
+/// Applies the changes of an edited code file to the source map of the generated file.
+/// Added lines belong to the block of the preceding line.
+fn update_source_map(source_map: &str, edited: &str) -> String {
+    let (label_start, _, _) = source_map_labels().label_prefixes();
+    let is_label = |line: &str| line.trim_start().starts_with(SOURCE_MAP_COMMENT);
+
+    let labeled: Vec<_> = source_map.lines().collect();
+    let code_lines: Vec<_> = (0..labeled.len())
+        .filter(|idx| !is_label(labeled[*idx]))
+        .collect();
+    let generated: Vec<_> = code_lines.iter().map(|idx| labeled[*idx]).collect();
+    let edited: Vec<_> = edited.lines().collect();
+
+    // Copies the source map's lines up to `to`, which are labels only
+    fn flush<'a>(labeled: &[&'a str], result: &mut Vec<&'a str>, pos: &mut usize, to: usize) {
+        result.extend_from_slice(&labeled[*pos..to]);
+        *pos = to;
+    }
+
+    let mut result = vec![];
+    let mut pos = 0;
+
+    for op in capture_diff_slices(Algorithm::Myers, &generated, &edited) {
+        let (old_range, new_range) = (op.old_range(), op.new_range());
+        if let DiffOp::Equal { .. } = op {
+            for (old, new) in old_range.zip(new_range) {
+                flush(&labeled, &mut result, &mut pos, code_lines[old]);
+                result.push(edited[new]);
+                pos += 1;
+            }
+            continue;
+        }
+
+        for old in old_range {
+            flush(&labeled, &mut result, &mut pos, code_lines[old]);
+            pos += 1;
+        }
+        if !new_range.is_empty() && result.is_empty() {
+            let first = (pos..labeled.len())
+                .find(|idx| !labeled[*idx].trim_start().starts_with(&label_start))
+                .unwrap_or(labeled.len());
+            flush(&labeled, &mut result, &mut pos, first);
+        }
+        result.extend(new_range.map(|new| edited[new]));
+    }
+    flush(&labeled, &mut result, &mut pos, labeled.len());
+
+    let mut out = result.iter().join("\n", "").to_string();
+    out.push('\n');
+    out
+}
+
+fn insert_blocks(code_blocks: &mut Occurrences, blocks: Vec<RevCodeBlock>, code_file: &Path) {
+    for mut block in blocks.into_iter() {
+        block.code_file = code_file.to_owned();
+        code_blocks
+            .entry((PathBuf::from(&block.file), block.name.clone(), block.index))
+            .or_default()
+            .push(block);
+    }
+}
+
+/// Selects one occurrence of each code block. For blocks with differing occurrences,
+/// the occurrences changed relative to the given base are adopted, if they are all equal.
+pub fn resolve_occurrences(
+    occurrences: Occurrences,
+    base: Option<&HashMap<BlockKey, RevCodeBlock>>,
+) -> Fallible<HashMap<BlockKey, RevCodeBlock>> {
+    let mut code_blocks = HashMap::new();
+    let mut conflicts = vec![];
+
+    for (key, mut blocks) in occurrences {
+        let block_name = format!(
+            "{} # {} # {}",
+            key.0.display(),
+            key.1.as_deref().unwrap_or(""),
+            key.2
+        );
+
+        if blocks.len() > 1 {
+            if blocks.iter().all(|block| block.lines == blocks[0].lines) {
+                warn!("Multiple occurrences of a code block: {}", block_name);
+            } else {
+                let base_lines = base.and_then(|base| base.get(&key)).map(|b| &b.lines);
+                let changed: Vec<_> = (0..blocks.len())
+                    .filter(|idx| base_lines != Some(&blocks[*idx].lines))
+                    .collect();
+                let first = changed[0];
+
+                if base_lines.is_some()
+                    && changed
+                        .iter()
+                        .all(|idx| blocks[*idx].lines == blocks[first].lines)
+                {
+                    info!(
+                        "Adopting changes to code block {} from {}",
+                        block_name,
+                        changed
+                            .iter()
+                            .map(|idx| blocks[*idx].location())
+                            .join(", ", "")
+                    );
+                    blocks.swap(0, first);
+                } else {
+                    conflicts.push(format!(
+                        "{}, changed in {}",
+                        block_name,
+                        changed
+                            .iter()
+                            .map(|idx| blocks[*idx].location())
+                            .join(", ", "")
+                    ));
+                    continue;
+                }
+            }
+        }
+
+        code_blocks.insert(key, blocks.swap_remove(0));
+    }
+
+    if conflicts.is_empty() {
+        Ok(code_blocks)
+    } else {
+        conflicts.sort();
+        Err(format!(
+            "Reverse mode impossible due to multiple, differing occurrences of code blocks:\n  {}",
+            conflicts.iter().join("\n  ", "")
+        )
+        .into())
+    }
+}
+
+/// Kind of a block label
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelKind {
+    /// Start of a block
+    Start,
+    /// Start of the next block of the same name, ending the previous one
+    Next,
+    /// End of a block
+    End,
+}
+
+/// A block label in the code output
+#[derive(Debug, PartialEq, Eq)]
+pub struct Label {
+    /// Kind of the label
+    pub kind: LabelKind,
+    /// Doc source file
+    pub file: String,
+    /// Block name
+    pub name: Option<String>,
+    /// Block index
+    pub index: usize,
+}
+
+impl Label {
+    /// Key of the labelled block
+    pub fn key(&self) -> BlockKey {
+        (PathBuf::from(&self.file), self.name.clone(), self.index)
+    }
+}
+
+/// Parses a block label from a line with leading whitespace removed.
+/// Returns `None` if the line is not a block label.
+pub fn parse_label(trimmed: &str, block_labels: &BlockLabels) -> Option<Fallible<Label>> {
     let (start, next, end) = block_labels.label_prefixes();
     let block_name_sep = "#";
 
+    let (kind, rest) = if let Some(rest) = trimmed.strip_prefix(&next) {
+        (LabelKind::Next, rest)
+    } else if let Some(rest) = trimmed.strip_prefix(&start) {
+        (LabelKind::Start, rest)
+    } else if let Some(rest) = trimmed.strip_prefix(&end) {
+        (LabelKind::End, rest)
+    } else {
+        return None;
+    };
+
+    let mut full_name = rest.trim();
+    if let Some(comment_end) = &block_labels.comment_end {
+        if let Some(idx) = full_name.find(comment_end.as_str()) {
+            full_name = full_name[..idx].trim();
+        }
+    }
+
+    let mut parts = full_name.splitn(3, block_name_sep);
+    let file = parts.next().unwrap_or("").to_string();
+    let name = parts.next().and_then(|s| {
+        if s.is_empty() {
+            None
+        } else {
+            Some(s.to_string())
+        }
+    });
+    let index = match parts.next() {
+        Some(index_str) => index_str.parse::<usize>().map_err(|_| {
+            format!(
+                "Can't parse block index '{}' to an integer in {}",
+                index_str, full_name
+            )
+            .into()
+        }),
+        None => Err(format!("Missing block index in {}", full_name).into()),
+    };
+
+    Some(index.map(|index| Label {
+        kind,
+        file,
+        name,
+        index,
+    }))
+}
+
+fn parse(
+    source: &str,
+    parser: &ParserSettings,
+    block_labels: &BlockLabels,
+) -> Fallible<(Vec<RevCodeBlock>, Vec<Unlabeled>)> {
     let mut blocks = vec![];
     let mut block_stack: Vec<RevCodeBlock> = vec![];
-    for line in source.lines() {
+
+    let mut unlabeled = vec![];
+    let mut pending: Option<Unlabeled> = None;
+    let mut last_top_level: Option<BlockKey> = None;
+
+    // Labels of source maps are not part of the code file
+    let is_source_map = block_labels.comment_start == SOURCE_MAP_COMMENT;
+    let mut label_count = 0;
+
+    for (line_idx, line) in source.lines().enumerate() {
         let trimmed = line.trim_start();
         let indent = line.len() - trimmed.len();
-        if trimmed.starts_with(&start) || trimmed.starts_with(&next) {
-            let is_next = trimmed.starts_with(&next);
-            if is_next {
+        match parse_label(trimmed, block_labels).transpose()? {
+            Some(Label {
+                kind: LabelKind::End,
+                ..
+            }) => {
+                label_count += 1;
                 let block = block_stack.pop();
                 if let Some(block) = block {
+                    if block_stack.is_empty() {
+                        last_top_level =
+                            Some((PathBuf::from(&block.file), block.name.clone(), block.index));
+                    }
                     blocks.push(block);
                 }
             }
-
-            let mut full_name = if is_next {
-                trimmed[next.len()..].trim().to_string()
-            } else {
-                trimmed[start.len()..].trim().to_string()
-            };
-            if let Some(comment_end) = &block_labels.comment_end {
-                if let Some(idx) = full_name.find(comment_end) {
-                    full_name = full_name[..idx].trim().to_string();
+            Some(Label {
+                kind,
+                file,
+                name,
+                index,
+            }) => {
+                let is_next = kind == LabelKind::Next;
+                if is_next {
+                    let block = block_stack.pop();
+                    if let Some(block) = block {
+                        blocks.push(block);
+                    }
                 }
-            }
 
-            let mut parts = full_name.splitn(3, block_name_sep);
-            let file = parts.next().unwrap_or("").to_string();
-            let name = parts.next().and_then(|s| {
-                if s.is_empty() {
-                    None
-                } else {
-                    Some(s.to_string())
+                if !is_next {
+                    if let (Some(name), Some(block)) = (&name, block_stack.last_mut()) {
+                        let new_line = format!(
+                            "{}{}{}{}{}",
+                            &line[..indent],
+                            parser.macro_start,
+                            if parser.macro_start.ends_with(' ') {
+                                ""
+                            } else {
+                                " "
+                            },
+                            name,
+                            parser.macro_end,
+                        );
+                        block.push_line(new_line);
+                    }
                 }
-            });
-            let index_str = parts
-                .next()
-                .ok_or_else(|| format!("Missing block index in {}", full_name))?;
-            let index = index_str.parse::<usize>().map_err(|_| {
-                format!(
-                    "Can't parse block index '{}' to an integer in {}",
-                    index_str, full_name
-                )
-            })?;
-
-            if !is_next {
-                if let (Some(name), Some(block)) = (&name, block_stack.last_mut()) {
-                    let new_line = format!(
-                        "{}{}{}{}{}",
-                        &line[..indent],
-                        parser.macro_start,
-                        if parser.macro_start.ends_with(' ') {
-                            ""
-                        } else {
-                            " "
-                        },
-                        name,
-                        parser.macro_end,
-                    );
-                    block.push_line(new_line);
+
+                if block_stack.is_empty() {
+                    if let Some(mut lines) = pending.take() {
+                        lines.following = Some((PathBuf::from(&file), name.clone(), index));
+                        unlabeled.extend(trim_unlabeled(lines));
+                    }
                 }
-            }
 
-            let block = RevCodeBlock::new(file, name.clone(), index, line[..indent].to_string());
-            block_stack.push(block);
-        } else if trimmed.starts_with(&end) {
-            let block = block_stack.pop();
-            if let Some(block) = block {
-                blocks.push(block);
+                let first_line = if is_source_map {
+                    line_idx - label_count + 1
+                } else {
+                    line_idx + 2
+                };
+                label_count += 1;
+
+                let block =
+                    RevCodeBlock::new(file, name, index, line[..indent].to_string(), first_line);
+                block_stack.push(block);
             }
-        } else if let Some(block) = block_stack.last_mut() {
-            if line.starts_with(&block.indent) {
-                block.push_line(line[block.indent.len()..].to_string());
-            } else {
-                block.push_line(line.to_string());
+            None => {
+                if let Some(block) = block_stack.last_mut() {
+                    if line.starts_with(&block.indent) {
+                        block.push_line(line[block.indent.len()..].to_string());
+                    } else {
+                        block.push_line(line.to_string());
+                    }
+                } else {
+                    pending
+                        .get_or_insert_with(|| Unlabeled {
+                            code_file: PathBuf::new(),
+                            line: line_idx + 1,
+                            lines: vec![],
+                            preceding: last_top_level.clone(),
+                            following: None,
+                        })
+                        .lines
+                        .push(line.to_string());
+                }
             }
         }
     }
+    // Without any labels, the file was tangled with `--clean`, and there is nothing to play back
+    if label_count > 0 {
+        if let Some(lines) = pending {
+            unlabeled.extend(trim_unlabeled(lines));
+        }
+    }
 
-    Ok(blocks)
+    Ok((blocks, unlabeled))
+}
+
+/// Removes leading and trailing blank lines. Returns `None` if all lines are blank.
+fn trim_unlabeled(mut unlabeled: Unlabeled) -> Option<Unlabeled> {
+    let is_blank = |line: &String| line.trim().is_empty();
+    let first = unlabeled.lines.iter().position(|line| !is_blank(line))?;
+    let last = unlabeled.lines.iter().rposition(|line| !is_blank(line))?;
+
+    unlabeled.line += first;
+    unlabeled.lines = unlabeled.lines.drain(first..=last).collect();
+    Some(unlabeled)
 }
 
 #[cfg(test)]
@@ -188,7 +628,7 @@ mod tests {
         let code = r#"
 fn main() {}
 "#;
-        let blocks = parse(code, &config.parser, &labels).unwrap();
+        let (blocks, _) = parse(code, &config.parser, &labels).unwrap();
 
         assert_eq!(blocks.len(), 0);
     }
@@ -203,7 +643,7 @@ fn main() {}
 fn main() {}
 // @>README.md##0
 "#;
-        let blocks = parse(code, &config.parser, &labels).unwrap();
+        let (blocks, _) = parse(code, &config.parser, &labels).unwrap();
 
         assert_eq!(blocks.len(), 1);
         assert_eq!(blocks[0].name, None);
@@ -222,7 +662,7 @@ fn main() {}
 fn main() {}
 // @>README.md#Block name#0
 "#;
-        let blocks = parse(code, &config.parser, &labels).unwrap();
+        let (blocks, _) = parse(code, &config.parser, &labels).unwrap();
 
         assert_eq!(blocks.len(), 1);
         assert_eq!(blocks[0].name, Some("Block name".to_owned()));
@@ -244,7 +684,7 @@ fn print() {}
 // @>README.md#Inner#0
 // @>README.md##0
 "#;
-        let blocks = parse(code, &config.parser, &labels).unwrap();
+        let (blocks, _) = parse(code, &config.parser, &labels).unwrap();
 
         assert_eq!(blocks.len(), 2);
 
@@ -274,7 +714,7 @@ fn beep() {}
 // @>README.md#Inner#1
 // @>README.md##0
 "#;
-        let blocks = parse(code, &config.parser, &labels).unwrap();
+        let (blocks, _) = parse(code, &config.parser, &labels).unwrap();
 
         assert_eq!(blocks.len(), 3);
 
@@ -294,6 +734,102 @@ fn beep() {}
         assert_eq!(blocks[2].lines, vec!["fn main() {}", "// ==> Inner."]);
     }
 
+    #[test]
+    fn unlabeled_lines() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let labels = default_block_labels();
+
+        let code = r#"// License
+
+// <@README.md##0
+fn main() {}
+// @>README.md##0
+fn added() {}
+"#;
+        let (blocks, unlabeled) = parse(code, &config.parser, &labels).unwrap();
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(unlabeled.len(), 2);
+
+        let key = (PathBuf::from("README.md"), None, 0);
+        assert_eq!(unlabeled[0].line, 1);
+        assert_eq!(unlabeled[0].lines, vec!["// License"]);
+        assert_eq!(unlabeled[0].preceding, None);
+        assert_eq!(unlabeled[0].following, Some(key.clone()));
+
+        assert_eq!(unlabeled[1].line, 6);
+        assert_eq!(unlabeled[1].lines, vec!["fn added() {}"]);
+        assert_eq!(unlabeled[1].preceding, Some(key));
+        assert_eq!(unlabeled[1].following, None);
+    }
+
+    #[test]
+    fn no_unlabeled_lines_without_labels() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let labels = default_block_labels();
+
+        let code = r#"fn main() {}
+fn added() {}
+"#;
+        let (blocks, unlabeled) = parse(code, &config.parser, &labels).unwrap();
+
+        assert!(blocks.is_empty());
+        assert!(unlabeled.is_empty());
+    }
+
+    #[test]
+    fn adopt_single_changed_occurrence() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let labels = default_block_labels();
+
+        let code = r#"
+// <@README.md##0
+// <@README.md#Inner#0
+fn print() {}
+// @>README.md#Inner#0
+// <@README.md#Inner#0
+fn print_changed() {}
+// @>README.md#Inner#0
+// @>README.md##0
+"#;
+        let key = (PathBuf::from("README.md"), Some("Inner".to_string()), 0);
+
+        let (blocks, _) = parse(code, &config.parser, &labels).unwrap();
+        let mut occurrences = Occurrences::new();
+        insert_blocks(&mut occurrences, blocks, Path::new("code/main.rs"));
+
+        let mut base_block =
+            RevCodeBlock::new("README.md".to_string(), key.1.clone(), 0, String::new(), 1);
+        base_block.push_line("fn print() {}".to_string());
+        let mut base = HashMap::new();
+        base.insert(key.clone(), base_block);
+
+        let resolved = resolve_occurrences(occurrences, Some(&base)).unwrap();
+        assert_eq!(resolved[&key].lines, vec!["fn print_changed() {}"]);
+        assert_eq!(resolved[&key].location(), "code/main.rs:7");
+    }
+
+    #[test]
+    fn update_source_map_with_edits() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+
+        let source_map = "\u{1e} <@README.md##0\nfn main() {}\n\u{1e} <@README.md#Inner#0\nfn print() {}\n\u{1e} @>README.md#Inner#0\n\u{1e} @>README.md##0\n";
+        let edited = "// Main\nfn main() {}\nfn print() {}\nfn beep() {}\n";
+
+        let updated = update_source_map(source_map, edited);
+        assert_eq!(
+            updated,
+            "\u{1e} <@README.md##0\n// Main\nfn main() {}\n\u{1e} <@README.md#Inner#0\nfn print() {}\nfn beep() {}\n\u{1e} @>README.md#Inner#0\n\u{1e} @>README.md##0\n"
+        );
+
+        let mut blocks = HashMap::new();
+        insert_source_map_blocks(&updated, Path::new("code/main.rs"), &config, &mut blocks)
+            .unwrap();
+        let inner = &blocks[&(PathBuf::from("README.md"), Some("Inner".to_string()), 0)][0];
+        assert_eq!(inner.lines, vec!["fn print() {}", "fn beep() {}"]);
+        assert_eq!(inner.location(), "code/main.rs:3");
+    }
+
     fn default_block_labels() -> BlockLabels {
         BlockLabels {
             comment_start: "//".to_string(),
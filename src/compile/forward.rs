@@ -3,24 +3,37 @@ use std::{
         hash_map::Entry::{Occupied, Vacant},
         HashMap, HashSet,
     },
-    fs,
     path::{Path, PathBuf},
 };
 
 use log::{info, warn};
-use yarner_lib::{Document, Node, Transclusion};
+use yarner_lib::{Document, ExtraFile, FileContent, Node, OutputTarget, Transclusion};
 
 use crate::{
     config::{Config, ParserSettings},
-    files, parse, print,
+    files,
+    output::Output,
+    parse, print,
     util::Fallible,
 };
 
+/// A link or transclusion from one document to another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// The referencing document
+    pub from: PathBuf,
+    /// The referenced document
+    pub to: PathBuf,
+    /// Whether the reference is a transclusion, or a followed link otherwise
+    pub is_transclusion: bool,
+}
+
 pub fn collect_documents(
     config: &Config,
     file_name: &Path,
     documents: &mut HashMap<PathBuf, Document>,
     source_files: &mut HashSet<PathBuf>,
+    references: &mut Vec<Reference>,
 ) -> Fallible {
     if !documents.contains_key(file_name) {
         let mut trace = HashSet::new();
@@ -30,6 +43,7 @@ pub fn collect_documents(
             file_name,
             &mut trace,
             source_files,
+            references,
         )?;
 
         let file_str = file_name.to_str().unwrap();
@@ -40,7 +54,7 @@ pub fn collect_documents(
         for file in links {
             if file.is_file() {
                 if !documents.contains_key(&file) {
-                    collect_documents(config, &file, documents, source_files)?;
+                    collect_documents(config, &file, documents, source_files, references)?;
                 }
             } else {
                 warn!("Link target not found for {}", file.display());
@@ -54,11 +68,12 @@ pub fn collect_documents(
 pub fn extract_code_all(
     config: &Config,
     documents: &HashMap<PathBuf, Document>,
+    output: &mut Output,
 ) -> Fallible<HashMap<PathBuf, Option<PathBuf>>> {
     let mut code_files = HashMap::new();
 
     for (path, doc) in documents.iter() {
-        extract_code(config, doc, path, &mut code_files)?;
+        extract_code(config, doc, path, &mut code_files, output)?;
     }
 
     Ok(code_files)
@@ -67,9 +82,10 @@ pub fn extract_code_all(
 pub fn write_documentation_all(
     config: &Config,
     documents: &HashMap<PathBuf, Document>,
+    output: &mut Output,
 ) -> Fallible {
     for (path, doc) in documents.iter() {
-        write_documentation(config, doc, path)?;
+        write_documentation(config, doc, path, output)?;
     }
     Ok(())
 }
@@ -79,94 +95,141 @@ fn extract_code(
     document: &Document,
     file_name: &Path,
     track_code_files: &mut HashMap<PathBuf, Option<PathBuf>>,
+    output: &mut Output,
 ) -> Fallible {
     info!("Extracting code from {}", file_name.display());
 
-    let mut entries = document.entry_points();
-
-    let file_name_without_ext = file_name.with_extension("");
-    entries.insert(
-        config.paths.entrypoint.as_deref(),
-        (&file_name_without_ext, Some(file_name.to_owned())),
-    );
+    let code_dir = match &config.paths.code {
+        Some(code_dir) => code_dir,
+        None => {
+            warn!("Missing output location for code, skipping code output.");
+            return Ok(());
+        }
+    };
 
-    let mut any_output = false;
-    for (entrypoint, (sub_file_name, sub_source_file)) in entries {
-        if let Some(code_dir) = &config.paths.code {
-            let code_blocks = document.code_blocks_by_name();
-            if let Some(entry_blocks) = code_blocks.get(&entrypoint) {
-                any_output = true;
-
-                let mut file_path = code_dir.to_owned();
-                file_path.push(sub_file_name);
-
-                let extension = file_path
-                    .extension()
-                    .and_then(|ext| ext.to_str())
-                    .unwrap_or("")
-                    .to_string();
-                let settings = config.language.get(&extension);
-
-                // TODO: only track files that are really created!
-                match track_code_files.entry(file_path.clone()) {
-                    Occupied(entry) => {
-                        if sub_source_file == *entry.get() {
-                            info!("  Skipping file {} (already written)", file_path.display());
-                            continue;
-                        } else {
-                            return Err(format!(
-                                "Multiple distinct locations point to code file {}",
-                                file_path.display()
-                            )
-                            .into());
-                        }
-                    }
-                    Vacant(entry) => {
-                        entry.insert(sub_source_file);
-                    }
-                }
+    let code_blocks = document.code_blocks_by_name();
+    let entries = code_entries(config, document, file_name, code_dir);
+    if entries.is_empty() {
+        warn!(
+            "  No entrypoint for file {}, skipping code output.",
+            file_name.display()
+        );
+    }
 
-                let code = print::code::print_code(
-                    &code_blocks,
-                    entry_blocks,
-                    settings,
-                    document.newline(),
-                )?;
-
-                if files::file_differs(&file_path, &code) {
-                    info!("  Writing file {}", file_path.display());
-                    fs::create_dir_all(file_path.parent().unwrap())?;
-                    fs::write(&file_path, code)?;
+    for (entrypoint, file_path, sub_source_file) in entries {
+        let entry_blocks = &code_blocks[&entrypoint];
+        let extension = file_path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_string();
+        let settings = config.language.get(&extension);
+
+        // TODO: only track files that are really created!
+        match track_code_files.entry(file_path.clone()) {
+            Occupied(entry) => {
+                if sub_source_file == *entry.get() {
+                    info!("  Skipping file {} (already written)", file_path.display());
+                    continue;
                 } else {
-                    info!("  Skipping unchanged file {}", file_path.display());
+                    return Err(format!(
+                        "Multiple distinct locations point to code file {}",
+                        file_path.display()
+                    )
+                    .into());
                 }
             }
+            Vacant(entry) => {
+                output.record_origin(&file_path, sub_source_file.as_deref().unwrap_or(file_name));
+                entry.insert(sub_source_file);
+            }
+        }
+
+        let code =
+            print::code::print_code(&code_blocks, entry_blocks, settings, document.newline())?;
+
+        if settings.is_some_and(|settings| settings.has_labels()) {
+            output.record_labeled(&file_path);
+        }
+        if let Some(settings) = settings.filter(|settings| settings.has_source_map()) {
+            let source_map = print::code::print_source_map(
+                &code_blocks,
+                entry_blocks,
+                settings,
+                document.newline(),
+            )?;
+            output.record_source_map(&file_path, source_map);
+        }
+
+        if output.write(&file_path, &code)? {
+            info!("  Writing file {}", file_path.display());
         } else {
-            warn!("Missing output location for code, skipping code output.");
+            info!("  Skipping unchanged file {}", file_path.display());
         }
     }
 
-    if !any_output {
-        warn!(
-            "  No entrypoint for file {}, skipping code output.",
-            file_name.display()
-        );
+    Ok(())
+}
+
+/// Entrypoints of a document that produce code, with the path of the code file and the source file
+fn code_entries<'a>(
+    config: &'a Config,
+    document: &'a Document,
+    file_name: &Path,
+    code_dir: &Path,
+) -> Vec<(Option<&'a str>, PathBuf, Option<PathBuf>)> {
+    let code_blocks = document.code_blocks_by_name();
+    let mut entries = document.entry_points();
+
+    let file_name_without_ext = file_name.with_extension("");
+    entries.insert(
+        config.paths.entrypoint.as_deref(),
+        (&file_name_without_ext, Some(file_name.to_owned())),
+    );
+
+    entries
+        .into_iter()
+        .filter(|(entrypoint, _)| code_blocks.contains_key(entrypoint))
+        .map(|(entrypoint, (sub_file_name, sub_source_file))| {
+            (entrypoint, code_dir.join(sub_file_name), sub_source_file)
+        })
+        .collect()
+}
+
+/// Code files produced from a document
+pub fn code_files(config: &Config, document: &Document, file_name: &Path) -> Vec<PathBuf> {
+    match &config.paths.code {
+        Some(code_dir) => code_entries(config, document, file_name, code_dir)
+            .into_iter()
+            .map(|(_, path, _)| path)
+            .collect(),
+        None => vec![],
     }
+}
 
-    Ok(())
+/// Documentation file produced from a document
+pub fn docs_file(config: &Config, file_name: &Path) -> Option<PathBuf> {
+    config
+        .paths
+        .docs
+        .as_ref()
+        .map(|doc_dir| doc_dir.join(file_name))
 }
 
-fn write_documentation(config: &Config, document: &Document, file_name: &Path) -> Fallible {
+fn write_documentation(
+    config: &Config,
+    document: &Document,
+    file_name: &Path,
+    output: &mut Output,
+) -> Fallible {
     match &config.paths.docs {
         Some(doc_dir) => {
             let documentation = print::docs::print_docs(document, &config.parser);
             let mut file_path = doc_dir.to_owned();
             file_path.push(file_name);
 
-            if files::file_differs(&file_path, &documentation) {
+            if output.write(&file_path, &documentation)? {
                 info!("Writing documentation file {}", file_name.display());
-                fs::create_dir_all(file_path.parent().unwrap()).unwrap();
-                fs::write(&file_path, documentation)?;
             } else {
                 info!(
                     "Skipping unchanged documentation file {}",
@@ -180,12 +243,73 @@ fn write_documentation(config: &Config, document: &Document, file_name: &Path) -
     Ok(())
 }
 
+/// Writes the additional files produced by plugins, and returns the written code files.
+/// Files for the code output are skipped if `write_code` is `false`.
+pub fn write_extra_files(
+    config: &Config,
+    extra_files: &HashMap<PathBuf, ExtraFile>,
+    documents: &HashMap<PathBuf, Document>,
+    code_files: &HashMap<PathBuf, Option<PathBuf>>,
+    write_code: bool,
+    output: &mut Output,
+) -> Fallible<HashSet<PathBuf>> {
+    let mut written_code_files = HashSet::new();
+
+    let mut files: Vec<_> = extra_files.iter().collect();
+    files.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (path, file) in files {
+        let (out_dir, is_generated) = match file.target {
+            OutputTarget::Docs => (&config.paths.docs, documents.contains_key(path)),
+            OutputTarget::Code if write_code => (&config.paths.code, false),
+            OutputTarget::Code => continue,
+        };
+        let out_dir = match out_dir {
+            Some(out_dir) => out_dir,
+            None => {
+                warn!(
+                    "Missing output location for extra file {}, skipping.",
+                    path.display()
+                );
+                continue;
+            }
+        };
+
+        let file_path = out_dir.join(path);
+        if is_generated || code_files.contains_key(&file_path) {
+            return Err(format!(
+                "Extra file {} from plugins collides with an output file of the documents",
+                file_path.display()
+            )
+            .into());
+        }
+
+        let content = match &file.content {
+            FileContent::Document(document) => print::docs::print_docs(document, &config.parser),
+            FileContent::Text(text) => text.clone(),
+        };
+
+        if output.write(&file_path, &content)? {
+            info!("Writing extra file {}", file_path.display());
+        } else {
+            info!("Skipping unchanged extra file {}", file_path.display());
+        }
+
+        if file.target == OutputTarget::Code {
+            written_code_files.insert(file_path);
+        }
+    }
+
+    Ok(written_code_files)
+}
+
 fn transclude(
     parser: &ParserSettings,
     root_file: &Path,
     file_name: &Path,
     trace: &mut HashSet<PathBuf>,
     source_files: &mut HashSet<PathBuf>,
+    references: &mut Vec<Reference>,
 ) -> Fallible<(Document, Vec<PathBuf>)> {
     if trace.contains(file_name) {
         return Err(format!(
@@ -202,14 +326,32 @@ fn transclude(
     let (mut document, mut links) =
         parse::parse(&source_main, root_file, file_name, false, parser)?;
 
+    references.extend(links.iter().map(|link| Reference {
+        from: file_name.to_owned(),
+        to: link.to_owned(),
+        is_transclusion: false,
+    }));
+
     let transclusions = document.transclusions().cloned().collect::<Vec<_>>();
 
     let mut trans_so_far = HashSet::new();
     for trans in transclusions {
         if !trans_so_far.contains(&trans.file) {
             source_files.insert(trans.file.to_owned());
-
-            let (doc, sub_links) = transclude(parser, root_file, &trans.file, trace, source_files)?;
+            references.push(Reference {
+                from: file_name.to_owned(),
+                to: trans.file.to_owned(),
+                is_transclusion: true,
+            });
+
+            let (doc, sub_links) = transclude(
+                parser,
+                root_file,
+                &trans.file,
+                trace,
+                source_files,
+                references,
+            )?;
 
             if doc.newline() != document.newline() {
                 return Err(format!(
@@ -227,7 +369,7 @@ fn transclude(
             );
             transclude_into(&mut document, &trans, doc, &path);
 
-            links.extend(sub_links.into_iter());
+            links.extend(sub_links);
             trans_so_far.insert(trans.file.clone());
         } else {
             return Err(format!("Multiple transclusions of {}", trans.file.display()).into());
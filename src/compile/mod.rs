@@ -1,8 +1,17 @@
 use yarner_lib::{Document, Node};
 
+use crate::config::Config;
+
 pub mod forward;
 pub mod reverse;
 
+/// Names of all entrypoints of a document, including the configured (or unnamed) entrypoint
+pub fn entrypoint_names<'a>(config: &'a Config, document: &'a Document) -> Vec<Option<&'a str>> {
+    let mut entries: Vec<_> = document.entry_points().into_keys().collect();
+    entries.push(config.paths.entrypoint.as_deref());
+    entries
+}
+
 /// Sets the source file for all code blocks that have none
 fn set_source(document: &mut Document, source: &str) {
     for node in &mut document.nodes {
@@ -131,7 +131,7 @@ fn transclude_dry_run(
 
             compile(config, &doc, &trans.file, track_code_files);
 
-            links.extend(sub_links.into_iter());
+            links.extend(sub_links);
             documents.insert(trans.file.clone(), doc);
             trans_so_far.insert(trans.file.clone());
         } else {
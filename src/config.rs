@@ -1,12 +1,12 @@
 //! Config objects, to be read from Yarner.toml
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{de::Error as _, Deserialize, Deserializer};
 
-use crate::{files, util::Fallible};
+use crate::{files, plugin, util::Fallible};
 use toml::value::Table;
 
 pub const LINK_PATTERN: &str = r"\[([^\[\]]*)\]\((.*?)\)";
@@ -29,6 +29,9 @@ pub struct Config {
     /// TOML table of settings for plugins
     #[serde(default)]
     pub plugin: Table,
+    /// Config for the lint command
+    #[serde(default)]
+    pub lint: LintSettings,
 }
 
 impl Config {
@@ -44,6 +47,7 @@ impl Config {
         for language in self.language.values() {
             language.check()?;
         }
+        plugin::check_plugins(self)?;
 
         Ok(())
     }
@@ -51,7 +55,32 @@ impl Config {
     pub fn has_reverse_config(&self) -> bool {
         self.language
             .values()
-            .any(|lang| lang.block_labels.is_some())
+            .any(|lang| lang.block_labels.is_some() || lang.source_map)
+    }
+
+    /// Hash of the settings affecting the output, including command line overrides.
+    /// Excludes option `--clean`, as code output without labels is played back from source maps.
+    pub fn output_hash(&self) -> String {
+        let language: BTreeMap<_, _> = self
+            .language
+            .iter()
+            .map(|(name, lang)| {
+                let settings = yarner_lib::LanguageSettings {
+                    clean_code: false,
+                    ..lang.into()
+                };
+                (name, settings)
+            })
+            .collect();
+        let settings = serde_json::json!({
+            "parser": yarner_lib::ParserSettings::from(&self.parser),
+            "paths": yarner_lib::Paths::from(&self.paths),
+            "language": language,
+            "plugin": self.plugin,
+        });
+        blake3::hash(settings.to_string().as_bytes())
+            .to_hex()
+            .to_string()
     }
 }
 
@@ -130,6 +159,43 @@ impl Paths {
     }
 }
 
+/// Config for the lint command
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LintSettings {
+    /// Level for named blocks not reachable from any entrypoint
+    pub unreachable_block: LintLevel,
+    /// Level for macros referring to undefined blocks
+    pub undefined_macro: LintLevel,
+    /// Level for blocks of the same name with different fence languages
+    pub mixed_language: LintLevel,
+    /// Level for blocks without any code
+    pub empty_block: LintLevel,
+}
+
+impl Default for LintSettings {
+    fn default() -> Self {
+        Self {
+            unreachable_block: LintLevel::Warn,
+            undefined_macro: LintLevel::Error,
+            mixed_language: LintLevel::Warn,
+            empty_block: LintLevel::Warn,
+        }
+    }
+}
+
+/// Severity of a lint check
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LintLevel {
+    /// Check is disabled
+    Allow,
+    /// Findings are reported as warnings
+    Warn,
+    /// Findings are reported as errors
+    Error,
+}
+
 /// Config for a programming language
 #[derive(Deserialize, Default, Debug)]
 pub struct LanguageSettings {
@@ -139,6 +205,9 @@ pub struct LanguageSettings {
     pub clear_blank_lines: bool,
     /// Determines if code files should end with a blank line. Default: true.
     pub eof_newline: bool,
+    /// Records a hidden mapping of code lines to blocks, enabling reverse mode without block labels. Default: false.
+    #[serde(default)]
+    pub source_map: bool,
     /// Print code without block labels.
     #[serde(skip)]
     pub clean_code: bool,
@@ -153,6 +222,19 @@ impl LanguageSettings {
             Ok(())
         }
     }
+
+    /// Whether code output is printed with block labels
+    pub fn has_labels(&self) -> bool {
+        self.block_labels.is_some() && !self.clean_code
+    }
+
+    /// Whether code output is printed without labels, but with a source map for reverse mode
+    pub fn has_source_map(&self) -> bool {
+        match &self.block_labels {
+            Some(_) => self.clean_code,
+            None => self.source_map,
+        }
+    }
 }
 
 /// Config for block labels for a programming language
@@ -197,6 +279,63 @@ impl BlockLabels {
     }
 }
 
+impl From<&ParserSettings> for yarner_lib::ParserSettings {
+    fn from(settings: &ParserSettings) -> Self {
+        Self {
+            fence_sequence: settings.fence_sequence.clone(),
+            fence_sequence_alt: settings.fence_sequence_alt.clone(),
+            block_name_prefix: settings.block_name_prefix.clone(),
+            macro_start: settings.macro_start.clone(),
+            macro_end: settings.macro_end.clone(),
+            transclusion_start: settings.transclusion_start.clone(),
+            transclusion_end: settings.transclusion_end.clone(),
+            link_prefix: settings.link_following_pattern.0.clone(),
+            file_prefix: settings.file_prefix.clone(),
+            hidden_prefix: settings.hidden_prefix.clone(),
+        }
+    }
+}
+
+impl From<&Paths> for yarner_lib::Paths {
+    fn from(paths: &Paths) -> Self {
+        Self {
+            root: paths.root.clone(),
+            code: paths.code.clone(),
+            docs: paths.docs.clone(),
+            files: paths.files.clone(),
+            code_files: paths.code_files.clone(),
+            code_paths: paths.code_paths.clone(),
+            doc_files: paths.doc_files.clone(),
+            doc_paths: paths.doc_paths.clone(),
+            entrypoint: paths.entrypoint.clone(),
+        }
+    }
+}
+
+impl From<&LanguageSettings> for yarner_lib::LanguageSettings {
+    fn from(settings: &LanguageSettings) -> Self {
+        Self {
+            block_labels: settings.block_labels.as_ref().map(|labels| labels.into()),
+            clear_blank_lines: settings.clear_blank_lines,
+            eof_newline: settings.eof_newline,
+            source_map: settings.source_map,
+            clean_code: settings.clean_code,
+        }
+    }
+}
+
+impl From<&BlockLabels> for yarner_lib::BlockLabels {
+    fn from(labels: &BlockLabels) -> Self {
+        Self {
+            comment_start: labels.comment_start.clone(),
+            comment_end: labels.comment_end.clone(),
+            block_start: labels.block_start.clone(),
+            block_next: labels.block_next.clone(),
+            block_end: labels.block_end.clone(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -9,17 +9,17 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::util::Fallible;
+use crate::{output::Output, util::Fallible};
 
 pub fn read_file_string(path: &Path) -> Fallible<String> {
-    std::fs::read_to_string(&path).map_err(|err| format!("{}: {}", err, path.display()).into())
+    std::fs::read_to_string(path).map_err(|err| format!("{}: {}", err, path.display()).into())
 }
 
 pub fn read_file(path: &Path) -> Fallible<Vec<u8>> {
-    std::fs::read(&path).map_err(|err| format!("{}: {}", err, path.display()).into())
+    std::fs::read(path).map_err(|err| format!("{}: {}", err, path.display()).into())
 }
 
-fn files_differ(old: &Path, new: &Path) -> bool {
+pub fn files_differ(old: &Path, new: &Path) -> bool {
     read_file(old)
         .and_then(|old| read_file(new).map(|new| old != new))
         .unwrap_or(true)
@@ -34,6 +34,7 @@ pub fn copy_files(
     path_mod: Option<&[String]>,
     target_dir: &Path,
     reverse: bool,
+    output: &mut Output,
 ) -> Fallible<(HashSet<PathBuf>, HashSet<PathBuf>)> {
     match path_mod {
         Some(path_mod) if patterns.len() != path_mod.len() => {
@@ -83,21 +84,13 @@ pub fn copy_files(
                     }
                 }
 
-                if !reverse {
-                    std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
-                }
                 let (from, to) = if reverse {
                     (&file_path, &file)
                 } else {
                     (&file, &file_path)
                 };
-                if files_differ(from, to) {
+                if output.copy(from, to)? {
                     info!("Copying file {} to {}", from.display(), to.display());
-                    if let Err(err) = std::fs::copy(&from, &to) {
-                        return Err(
-                            format!("Error copying file {}: {}", file.display(), err).into()
-                        );
-                    }
                 } else {
                     info!(
                         "Skipping copy unchanged file {} to {}",
@@ -114,6 +107,33 @@ pub fn copy_files(
     ))
 }
 
+/// Removes the parent directories of the given files, as far as they are empty.
+/// The given root directories are never removed.
+pub fn remove_empty_dirs(files: &[PathBuf], roots: &[PathBuf]) -> Fallible {
+    for file in files {
+        let root = match roots.iter().find(|root| file.starts_with(root)) {
+            Some(root) => root,
+            None => continue,
+        };
+        let mut dir = file.parent();
+        while let Some(d) = dir {
+            if d == root.as_path() || !d.starts_with(root) || !is_empty_dir(d) {
+                break;
+            }
+            std::fs::remove_dir(d)
+                .map_err(|err| format!("Error removing directory {}: {}", d.display(), err))?;
+            dir = d.parent();
+        }
+    }
+    Ok(())
+}
+
+fn is_empty_dir(dir: &Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or(false)
+}
+
 fn modify_path(path: &Path, replace: &str) -> PathBuf {
     if replace.is_empty() || replace == "_" {
         return path.to_owned();
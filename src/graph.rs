@@ -0,0 +1,424 @@
+//! Dependency graphs of code blocks and documents, for export to DOT, Mermaid and JSON
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+use yarner_lib::{Document, Line};
+
+use crate::{
+    compile::{self, forward::Reference},
+    config::Config,
+    util::Fallible,
+};
+
+/// Output format for graphs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("Unknown graph format '{}'", s)),
+        }
+    }
+}
+
+/// Kind of a graph node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    /// A block that is an entrypoint for code output
+    Entrypoint,
+    /// A named block
+    Block,
+    /// A block referenced by a macro, but not defined
+    Undefined,
+    /// A document
+    Document,
+}
+
+/// Kind of a graph edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    /// A macro invocation of a block
+    Macro,
+    /// A followed link to a document
+    Link,
+    /// A transclusion of a document
+    Transclusion,
+}
+
+/// A node in a dependency graph
+#[derive(Debug, Serialize)]
+pub struct GraphNode {
+    /// Unique ID of the node
+    pub id: String,
+    /// Display label of the node
+    pub label: String,
+    /// Kind of the node
+    pub kind: NodeKind,
+    /// Document the node belongs to, for block graphs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// An edge in a dependency graph
+#[derive(Debug, Serialize)]
+pub struct GraphEdge {
+    /// ID of the source node
+    pub from: String,
+    /// ID of the target node
+    pub to: String,
+    /// Kind of the edge
+    pub kind: EdgeKind,
+}
+
+/// A dependency graph of blocks or documents
+#[derive(Debug, Default, Serialize)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+/// Creates the graph of macro references between code blocks
+pub fn block_graph(config: &Config, documents: &HashMap<PathBuf, Document>) -> Graph {
+    let mut paths: Vec<_> = documents.keys().collect();
+    paths.sort();
+
+    let mut graph = Graph::default();
+    for path in paths {
+        let document = &documents[path];
+        let doc_name = path.display().to_string();
+        let code_blocks = document.code_blocks_by_name();
+        let entries = compile::entrypoint_names(config, document);
+
+        let block_id = |name: Option<&str>| format!("{}#{}", doc_name, name.unwrap_or(""));
+
+        let mut names: Vec<_> = code_blocks.keys().collect();
+        names.sort();
+
+        let mut undefined = BTreeSet::new();
+        let mut edges = BTreeSet::new();
+        for name in names {
+            graph.nodes.push(GraphNode {
+                id: block_id(*name),
+                label: name.unwrap_or("(unnamed)").to_string(),
+                kind: if entries.contains(name) {
+                    NodeKind::Entrypoint
+                } else {
+                    NodeKind::Block
+                },
+                group: Some(doc_name.clone()),
+            });
+
+            for block in &code_blocks[name] {
+                for line in &block.source {
+                    if let Line::Macro { name: target, .. } = line {
+                        if !code_blocks.contains_key(&Some(target.as_str())) {
+                            undefined.insert(target.as_str());
+                        }
+                        edges.insert((block_id(*name), block_id(Some(target))));
+                    }
+                }
+            }
+        }
+
+        for name in undefined {
+            graph.nodes.push(GraphNode {
+                id: block_id(Some(name)),
+                label: name.to_string(),
+                kind: NodeKind::Undefined,
+                group: Some(doc_name.clone()),
+            });
+        }
+
+        graph
+            .edges
+            .extend(edges.into_iter().map(|(from, to)| GraphEdge {
+                from,
+                to,
+                kind: EdgeKind::Macro,
+            }));
+    }
+
+    graph
+}
+
+/// Creates the graph of links and transclusions between documents
+pub fn document_graph(documents: &HashMap<PathBuf, Document>, references: &[Reference]) -> Graph {
+    let nodes: BTreeSet<_> = documents
+        .keys()
+        .chain(references.iter().flat_map(|r| [&r.from, &r.to]))
+        .map(|path| path.display().to_string())
+        .collect();
+
+    let edges: BTreeSet<_> = references
+        .iter()
+        .map(|r| {
+            let kind = if r.is_transclusion {
+                EdgeKind::Transclusion
+            } else {
+                EdgeKind::Link
+            };
+            (
+                r.from.display().to_string(),
+                r.to.display().to_string(),
+                kind,
+            )
+        })
+        .collect();
+
+    Graph {
+        nodes: nodes
+            .into_iter()
+            .map(|path| GraphNode {
+                id: path.clone(),
+                label: path,
+                kind: NodeKind::Document,
+                group: None,
+            })
+            .collect(),
+        edges: edges
+            .into_iter()
+            .map(|(from, to, kind)| GraphEdge { from, to, kind })
+            .collect(),
+    }
+}
+
+impl Graph {
+    /// Formats the graph in the given format
+    pub fn format(&self, format: GraphFormat) -> Fallible<String> {
+        Ok(match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+            GraphFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    fn groups(&self) -> BTreeMap<Option<&str>, Vec<&GraphNode>> {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for node in &self.nodes {
+            groups.entry(node.group.as_deref()).or_default().push(node);
+        }
+        groups
+    }
+
+    /// Formats the graph in Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph yarner {{").unwrap();
+        writeln!(out, "    rankdir=LR;").unwrap();
+        writeln!(out, "    node [shape=box];").unwrap();
+
+        for (idx, (group, nodes)) in self.groups().into_iter().enumerate() {
+            let indent = if let Some(group) = group {
+                writeln!(out, "    subgraph \"cluster_{}\" {{", idx).unwrap();
+                writeln!(out, "        label={};", dot_quote(group)).unwrap();
+                "        "
+            } else {
+                "    "
+            };
+            for node in nodes {
+                let style = match node.kind {
+                    NodeKind::Entrypoint => ", style=bold",
+                    NodeKind::Undefined => ", style=dashed, color=red",
+                    NodeKind::Block | NodeKind::Document => "",
+                };
+                writeln!(
+                    out,
+                    "{}{} [label={}{}];",
+                    indent,
+                    dot_quote(&node.id),
+                    dot_quote(&node.label),
+                    style
+                )
+                .unwrap();
+            }
+            if group.is_some() {
+                writeln!(out, "    }}").unwrap();
+            }
+        }
+
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Transclusion => " [style=dashed]",
+                EdgeKind::Macro | EdgeKind::Link => "",
+            };
+            writeln!(
+                out,
+                "    {} -> {}{};",
+                dot_quote(&edge.from),
+                dot_quote(&edge.to),
+                style
+            )
+            .unwrap();
+        }
+
+        write!(out, "}}").unwrap();
+        out
+    }
+
+    /// Formats the graph as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        let ids: HashMap<_, _> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| (node.id.as_str(), format!("n{}", idx)))
+            .collect();
+
+        let mut out = String::new();
+        writeln!(out, "graph LR").unwrap();
+
+        for (idx, (group, nodes)) in self.groups().into_iter().enumerate() {
+            let indent = if let Some(group) = group {
+                writeln!(out, "    subgraph g{} [{}]", idx, mermaid_quote(group)).unwrap();
+                "        "
+            } else {
+                "    "
+            };
+            for node in nodes {
+                let (open, close) = match node.kind {
+                    NodeKind::Entrypoint => ("([", "])"),
+                    NodeKind::Undefined => ("{{", "}}"),
+                    NodeKind::Block | NodeKind::Document => ("[", "]"),
+                };
+                writeln!(
+                    out,
+                    "{}{}{}{}{}",
+                    indent,
+                    ids[node.id.as_str()],
+                    open,
+                    mermaid_quote(&node.label),
+                    close
+                )
+                .unwrap();
+            }
+            if group.is_some() {
+                writeln!(out, "    end").unwrap();
+            }
+        }
+
+        for edge in &self.edges {
+            let arrow = match edge.kind {
+                EdgeKind::Transclusion => "-.->",
+                EdgeKind::Macro | EdgeKind::Link => "-->",
+            };
+            writeln!(
+                out,
+                "    {} {} {}",
+                ids[edge.from.as_str()],
+                arrow,
+                ids[edge.to.as_str()]
+            )
+            .unwrap();
+        }
+
+        out.pop();
+        out
+    }
+}
+
+fn dot_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn mermaid_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "#quot;"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use std::path::Path;
+
+    const DOCUMENT: &str = r#"
+```rust
+fn main() {}
+// ==> Print.
+```
+
+```rust
+//- Print
+fn print() {}
+// ==> Missing.
+```
+"#;
+
+    fn graph() -> Graph {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let path = Path::new("README.md");
+        let (doc, _) = parse::parse(DOCUMENT, path, path, false, &config.parser).unwrap();
+
+        let mut documents = HashMap::new();
+        documents.insert(path.to_owned(), doc);
+        block_graph(&config, &documents)
+    }
+
+    #[test]
+    fn block_graph_nodes_and_edges() {
+        let graph = graph();
+
+        let nodes: Vec<_> = graph
+            .nodes
+            .iter()
+            .map(|n| (n.id.as_str(), n.kind))
+            .collect();
+        assert_eq!(
+            nodes,
+            vec![
+                ("README.md#", NodeKind::Entrypoint),
+                ("README.md#Print", NodeKind::Block),
+                ("README.md#Missing", NodeKind::Undefined),
+            ]
+        );
+
+        let edges: Vec<_> = graph
+            .edges
+            .iter()
+            .map(|e| (e.from.as_str(), e.to.as_str()))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                ("README.md#", "README.md#Print"),
+                ("README.md#Print", "README.md#Missing"),
+            ]
+        );
+    }
+
+    #[test]
+    fn format_mermaid() {
+        assert_eq!(
+            graph().to_mermaid(),
+            r#"graph LR
+    subgraph g0 ["README.md"]
+        n0(["(unnamed)"])
+        n1["Print"]
+        n2{{"Missing"}}
+    end
+    n0 --> n1
+    n1 --> n2"#
+        );
+    }
+
+    #[test]
+    fn format_dot() {
+        let dot = graph().to_dot();
+        assert!(dot.contains(r#"        "README.md#" [label="(unnamed)", style=bold];"#));
+        assert!(dot.contains(r#"    "README.md#Print" -> "README.md#Missing";"#));
+    }
+}
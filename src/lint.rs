@@ -0,0 +1,280 @@
+//! Checks documents for unreachable blocks, undefined macros and other problems
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+
+use yarner_lib::{CodeBlock, Document, Line};
+
+use crate::{
+    compile,
+    config::{Config, LintLevel},
+};
+
+/// Kind of a lint finding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintKind {
+    /// A block that is not reachable from any entrypoint
+    UnreachableBlock,
+    /// A macro referring to an undefined block
+    UndefinedMacro,
+    /// Blocks of the same name with different fence languages
+    MixedLanguage,
+    /// A block without any code
+    EmptyBlock,
+}
+
+impl LintKind {
+    /// Name of the check, as used in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            LintKind::UnreachableBlock => "unreachable_block",
+            LintKind::UndefinedMacro => "undefined_macro",
+            LintKind::MixedLanguage => "mixed_language",
+            LintKind::EmptyBlock => "empty_block",
+        }
+    }
+}
+
+/// A single lint finding
+#[derive(Debug)]
+pub struct Finding {
+    /// Kind of the finding
+    pub kind: LintKind,
+    /// Severity, as configured
+    pub level: LintLevel,
+    /// Source file of the finding
+    pub file: String,
+    /// Line number in the source file
+    pub line: usize,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{}:{}: {} [{}]",
+            self.file,
+            self.line,
+            self.message,
+            self.kind.name()
+        )
+    }
+}
+
+/// Runs all lint checks on the given documents
+pub fn lint(config: &Config, documents: &HashMap<PathBuf, Document>) -> Vec<Finding> {
+    let mut paths: Vec<_> = documents.keys().collect();
+    paths.sort();
+
+    let mut findings = vec![];
+    for path in paths {
+        lint_document(config, &documents[path], path, &mut findings);
+    }
+
+    findings
+        .into_iter()
+        .filter(|finding| finding.level != LintLevel::Allow)
+        .collect()
+}
+
+fn lint_document(config: &Config, document: &Document, path: &Path, findings: &mut Vec<Finding>) {
+    let settings = &config.lint;
+    let file_name = path.display().to_string();
+    let code_blocks = document.code_blocks_by_name();
+
+    let mut entries = compile::entrypoint_names(config, document);
+
+    let mut reachable = HashSet::new();
+    while let Some(name) = entries.pop() {
+        if !reachable.insert(name) {
+            continue;
+        }
+        for block in code_blocks.get(&name).into_iter().flatten() {
+            for line in &block.source {
+                if let Line::Macro { name, .. } = line {
+                    if code_blocks.contains_key(&Some(name.as_str())) {
+                        entries.push(Some(name));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut names: Vec<_> = code_blocks.keys().collect();
+    names.sort();
+
+    for name in names {
+        let blocks = &code_blocks[name];
+        let first = blocks[0];
+
+        if !reachable.contains(name) {
+            findings.push(Finding {
+                kind: LintKind::UnreachableBlock,
+                level: settings.unreachable_block,
+                file: block_file(first, &file_name),
+                line: first.line_number,
+                message: match name {
+                    Some(name) => format!("Block '{}' is not reachable from any entrypoint", name),
+                    None => "Unnamed blocks are not reachable from any entrypoint".to_string(),
+                },
+            });
+        }
+
+        let languages: BTreeSet<_> = blocks
+            .iter()
+            .filter_map(|block| block.language.as_deref())
+            .collect();
+        if languages.len() > 1 {
+            findings.push(Finding {
+                kind: LintKind::MixedLanguage,
+                level: settings.mixed_language,
+                file: block_file(first, &file_name),
+                line: first.line_number,
+                message: format!(
+                    "Blocks named '{}' use different languages: {}",
+                    name.unwrap_or(""),
+                    languages.into_iter().collect::<Vec<_>>().join(", ")
+                ),
+            });
+        }
+
+        for block in blocks {
+            if is_empty(block) {
+                findings.push(Finding {
+                    kind: LintKind::EmptyBlock,
+                    level: settings.empty_block,
+                    file: block_file(block, &file_name),
+                    line: block.line_number,
+                    message: format!("Block '{}' is empty", name.unwrap_or("")),
+                });
+            }
+
+            let mut undefined = BTreeMap::new();
+            for (idx, line) in block.source.iter().enumerate() {
+                if let Line::Macro { name, .. } = line {
+                    if !code_blocks.contains_key(&Some(name.as_str())) {
+                        undefined.insert(line_number(block, idx), name);
+                    }
+                }
+            }
+            for (line, macro_name) in undefined {
+                findings.push(Finding {
+                    kind: LintKind::UndefinedMacro,
+                    level: settings.undefined_macro,
+                    file: block_file(block, &file_name),
+                    line,
+                    message: format!("Macro '{}' refers to an undefined block", macro_name),
+                });
+            }
+        }
+    }
+}
+
+fn block_file(block: &CodeBlock, default: &str) -> String {
+    block
+        .source_file
+        .clone()
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Line number of a code line in the source file
+fn line_number(block: &CodeBlock, index: usize) -> usize {
+    if block.name.is_none() || block.is_unnamed {
+        block.line_number + index
+    } else {
+        block.line_number + index + 1
+    }
+}
+
+fn is_empty(block: &CodeBlock) -> bool {
+    block.source.iter().all(|line| match line {
+        Line::Source { source, .. } => source.trim().is_empty(),
+        Line::Macro { .. } => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn run_lint(text: &str) -> Vec<Finding> {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let path = Path::new("README.md");
+        let (doc, _) = parse::parse(text, path, path, false, &config.parser).unwrap();
+
+        let mut documents = HashMap::new();
+        documents.insert(path.to_owned(), doc);
+        lint(&config, &documents)
+    }
+
+    #[test]
+    fn clean_document() {
+        let findings = run_lint(
+            r#"
+```rust
+fn main() {}
+// ==> Print.
+```
+
+```rust
+//- Print
+fn print() {}
+```
+"#,
+        );
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn unreachable_and_undefined() {
+        let findings = run_lint(
+            r#"
+```rust
+fn main() {}
+// ==> Missing.
+```
+
+```rust
+//- Dead
+fn dead() {}
+```
+"#,
+        );
+        let kinds: Vec<_> = findings.iter().map(|f| (f.kind, f.line)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (LintKind::UndefinedMacro, 4),
+                (LintKind::UnreachableBlock, 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn mixed_language_and_empty() {
+        let findings = run_lint(
+            r#"
+```rust
+// ==> Part.
+```
+
+```rust
+//- Part
+```
+
+```python
+//- Part
+print()
+```
+"#,
+        );
+        let kinds: Vec<_> = findings.iter().map(|f| (f.kind, f.line)).collect();
+        assert_eq!(
+            kinds,
+            vec![(LintKind::MixedLanguage, 7), (LintKind::EmptyBlock, 7)]
+        );
+    }
+}
@@ -1,41 +1,353 @@
-use std::fs::write;
+use std::fs::{self, write};
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
-use crate::{files, util::Fallible};
-use std::collections::{BTreeMap, HashSet};
+use crate::{files, output::Output, util::Fallible};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-pub fn files_changed<P: AsRef<Path>>(lock_file: P, check_sources: bool) -> Fallible<bool> {
+/// Version of the lock file format
+const LOCK_VERSION: u32 = 2;
+
+/// Whether the last build recorded in the lock file used settings with a different hash,
+/// see [`Config::output_hash`](crate::config::Config::output_hash)
+pub fn config_changed<P: AsRef<Path>>(lock_file: P, config_hash: &str) -> Fallible<bool> {
+    if lock_file.as_ref().is_file() {
+        let lock = Lock::read(&lock_file)?;
+        Ok(lock
+            .config_hash
+            .is_some_and(|previous| previous != config_hash))
+    } else {
+        Ok(false)
+    }
+}
+
+/// Finds the source files, or the code files, that were changed since the last build
+pub fn files_changed<P: AsRef<Path>>(
+    lock_file: P,
+    check_sources: bool,
+) -> Fallible<HashSet<PathBuf>> {
     if lock_file.as_ref().is_file() {
         let lock = Lock::read(&lock_file)?;
-        let hashes = if check_sources {
-            lock.source_hashes
+        let entries = if check_sources {
+            lock.source_files
         } else {
-            lock.code_hashes
+            lock.code_files
         };
-        let current_hashes = hash_files(hashes.keys())?;
-        Ok(current_hashes != hashes)
+        let current_hashes = hash_files(entries.keys())?;
+        Ok(entries
+            .into_iter()
+            .filter(|(path, entry)| current_hashes.get(path) != Some(&entry.hash))
+            .map(|(path, _)| PathBuf::from(path))
+            .collect())
     } else {
-        Ok(false)
+        Ok(HashSet::new())
+    }
+}
+
+/// A code file recorded in the lock file
+pub struct LockedFile {
+    /// Path of the file
+    pub path: PathBuf,
+    /// Whether the file was modified since it was generated
+    pub modified: bool,
+}
+
+/// Finds all existing code files recorded in the lock file
+pub fn locked_code_files<P: AsRef<Path>>(lock_file: P) -> Fallible<Vec<LockedFile>> {
+    if !lock_file.as_ref().is_file() {
+        return Ok(vec![]);
+    }
+
+    let lock = Lock::read(&lock_file)?;
+
+    let mut files = vec![];
+    for (path, entry) in lock.code_files {
+        let file = PathBuf::from(&path);
+        if file.is_file() {
+            files.push(LockedFile {
+                modified: hash_file(&file)? != entry.hash,
+                path: file,
+            });
+        }
     }
+    Ok(files)
+}
+
+/// Finds code files of the previous build that are not among the given code files anymore
+pub fn stale_files<P: AsRef<Path>>(
+    lock_file: P,
+    code_files: &HashSet<PathBuf>,
+) -> Fallible<Vec<LockedFile>> {
+    let current: HashSet<_> = code_files.iter().map(|path| lock_key(path)).collect();
+
+    Ok(locked_code_files(lock_file)?
+        .into_iter()
+        .filter(|file| !current.contains(&lock_key(&file.path)))
+        .collect())
+}
+
+/// Removes code files of the previous build that are not produced anymore.
+/// Files modified since they were generated are kept, as are all files with `keep_stale`.
+/// Returns the kept files.
+pub fn remove_stale_files<P: AsRef<Path>>(
+    lock_file: P,
+    code_files: &HashSet<PathBuf>,
+    keep_stale: bool,
+    output: &mut Output,
+) -> Fallible<Vec<PathBuf>> {
+    let mut kept = vec![];
+    for stale in stale_files(lock_file, code_files)? {
+        if keep_stale {
+            warn!("Stale file {} is no longer produced", stale.path.display());
+            kept.push(stale.path);
+        } else if stale.modified {
+            warn!(
+                "Not removing stale file {}, as it was modified since it was generated",
+                stale.path.display()
+            );
+            kept.push(stale.path);
+        } else {
+            info!("Removing stale file {}", stale.path.display());
+            output.remove(&stale.path)?;
+        }
+    }
+    Ok(kept)
+}
+
+/// Removes the given code files from the lock file
+pub fn forget_code_files<P: AsRef<Path>>(lock_file: P, removed: &[PathBuf]) -> Fallible {
+    if !lock_file.as_ref().is_file() {
+        return Ok(());
+    }
+
+    let mut lock = Lock::read(&lock_file)?;
+    for path in removed {
+        lock.code_files.remove(&lock_key(path));
+    }
+    lock.write(&lock_file)
+}
+
+fn lock_key(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
 }
 
+/// Writes the lock file for the given files, with the origins of code files as recorded by `output`.
+/// Protected and kept files keep their entries of the previous lock file, as they were not written.
+/// With `keep_code_files`, code files of the previous lock not among `code_files` keep their entries,
+/// e.g. extra files of plugins in reverse mode.
+/// The previous config hash is kept while protected code files were generated with it.
 pub fn write_lock<P: AsRef<Path>>(
     lock_file: P,
+    config_hash: &str,
     source_files: &HashSet<PathBuf>,
     code_files: &HashSet<PathBuf>,
+    keep_code_files: bool,
+    output: &Output,
 ) -> Fallible {
-    let lock = Lock {
-        source_hashes: hash_files(source_files.iter())?,
-        code_hashes: hash_files(code_files.iter())?,
+    let previous = if lock_file.as_ref().is_file() {
+        Lock::read(&lock_file)?
+    } else {
+        Lock::default()
+    };
+
+    let protected: HashSet<_> = output.protected().union(output.kept()).cloned().collect();
+    let origins: HashMap<_, _> = output
+        .origins()
+        .iter()
+        .map(|(path, origin)| (lock_key(path), lock_key(origin)))
+        .collect();
+    let now = timestamp(SystemTime::now());
+
+    let is_written = |path: &&PathBuf| !protected.contains(*path);
+    let keeps_config = protected
+        .iter()
+        .any(|path| previous.code_files.contains_key(&lock_key(path)));
+    let mut lock = Lock {
+        version: LOCK_VERSION,
+        yarner_version: env!("CARGO_PKG_VERSION").to_string(),
+        config_hash: match previous.config_hash {
+            Some(previous) if keeps_config => Some(previous),
+            _ => Some(config_hash.to_string()),
+        },
+        source_files: file_entries(
+            hash_files(source_files.iter().filter(is_written))?,
+            &previous.source_files,
+            &HashMap::new(),
+            now,
+        ),
+        code_files: file_entries(
+            hash_files(code_files.iter().filter(is_written))?,
+            &previous.code_files,
+            &origins,
+            now,
+        ),
     };
+
+    // Includes kept stale files, which are not among `code_files`
+    for path in &protected {
+        let key = lock_key(path);
+        let (entries, previous_entries) = if source_files.contains(path) {
+            (&mut lock.source_files, &previous.source_files)
+        } else {
+            (&mut lock.code_files, &previous.code_files)
+        };
+        if let Some(entry) = previous_entries.get(&key) {
+            entries.insert(key, entry.clone());
+        }
+    }
+
+    if keep_code_files {
+        for (key, entry) in &previous.code_files {
+            if !lock.code_files.contains_key(key) && Path::new(key).is_file() {
+                lock.code_files.insert(key.clone(), entry.clone());
+            }
+        }
+    }
+
+    write_snapshots(
+        &lock_file,
+        &lock.code_hashes(),
+        output.labeled(),
+        output.source_maps(),
+        &protected,
+    )?;
     lock.write(&lock_file)
 }
 
-fn hash_files<'a, P: 'a>(files: impl Iterator<Item = &'a P>) -> Fallible<BTreeMap<String, String>>
+/// Creates lock entries for files with the given hashes.
+/// Files unchanged since the previous lock keep their generation time.
+fn file_entries(
+    hashes: BTreeMap<String, String>,
+    previous: &BTreeMap<String, FileEntry>,
+    origins: &HashMap<String, String>,
+    now: u64,
+) -> BTreeMap<String, FileEntry> {
+    hashes
+        .into_iter()
+        .map(|(path, hash)| {
+            let previous = previous.get(&path);
+            let entry = FileEntry {
+                generated: match previous {
+                    Some(previous) if previous.hash == hash => previous.generated,
+                    _ => now,
+                },
+                source: origins
+                    .get(&path)
+                    .cloned()
+                    .or_else(|| previous.and_then(|previous| previous.source.clone())),
+                hash,
+            };
+            (path, entry)
+        })
+        .collect()
+}
+
+/// Seconds since the Unix epoch
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Extension of source map files in the snapshot store
+const SOURCE_MAP_EXTENSION: &str = "map";
+
+/// Directory of the content-addressed store of generated code files, next to the lock file
+pub fn snapshot_dir<P: AsRef<Path>>(lock_file: P) -> PathBuf {
+    lock_file.as_ref().with_extension("snapshots")
+}
+
+/// Stores the content of code files with block labels and the source maps of code files by their hash,
+/// and removes snapshots that are no longer referenced.
+/// Other code files, like copied files, are not needed for merging and not stored.
+/// The content of protected files does not match their hash, and is not stored.
+fn write_snapshots<P: AsRef<Path>>(
+    lock_file: P,
+    code_hashes: &BTreeMap<String, String>,
+    labeled: &HashSet<PathBuf>,
+    source_maps: &HashMap<PathBuf, String>,
+    protected: &HashSet<PathBuf>,
+) -> Fallible {
+    let dir = snapshot_dir(&lock_file);
+    fs::create_dir_all(&dir)?;
+
+    let labeled: HashSet<_> = labeled.iter().map(|path| lock_key(path)).collect();
+    for (path, hash) in code_hashes {
+        let snapshot = dir.join(hash);
+        if !snapshot.is_file() && labeled.contains(path) && !protected.contains(Path::new(path)) {
+            fs::copy(path, &snapshot)
+                .map_err(|err| format!("Unable to store snapshot of file {}: {}", path, err))?;
+        }
+    }
+
+    let mut keep: HashSet<_> = code_hashes.values().cloned().collect();
+    for (path, source_map) in source_maps
+        .iter()
+        .filter(|(path, _)| !protected.contains(*path))
+    {
+        if let Some(hash) = code_hashes.get(&lock_key(path)) {
+            let file_name = format!("{}.{}", hash, SOURCE_MAP_EXTENSION);
+            write(dir.join(&file_name), source_map)?;
+            keep.insert(file_name);
+        }
+    }
+    // Source maps of protected files belong to their previous content
+    for path in protected {
+        if let Some(hash) = code_hashes.get(&lock_key(path)) {
+            keep.insert(format!("{}.{}", hash, SOURCE_MAP_EXTENSION));
+        }
+    }
+
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if !keep.contains(entry.file_name().to_string_lossy().as_ref()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Files in the snapshot store for the code files recorded in the lock file, by code file path.
+/// Code files without the respective snapshot file are not included.
+#[derive(Default)]
+pub struct Snapshots {
+    /// Last generated content of code files
+    pub code: HashMap<PathBuf, PathBuf>,
+    /// Source maps of code files generated without block labels
+    pub source_maps: HashMap<PathBuf, PathBuf>,
+}
+
+/// Finds the files in the snapshot store for all code files recorded in the lock file
+pub fn snapshots<P: AsRef<Path>>(lock_file: P) -> Fallible<Snapshots> {
+    if !lock_file.as_ref().is_file() {
+        return Ok(Snapshots::default());
+    }
+
+    let lock = Lock::read(&lock_file)?;
+    let dir = snapshot_dir(&lock_file);
+
+    let mut snapshots = Snapshots::default();
+    for (path, hash) in lock.code_hashes() {
+        let code = dir.join(&hash);
+        let source_map = code.with_extension(SOURCE_MAP_EXTENSION);
+        if code.is_file() {
+            snapshots.code.insert(PathBuf::from(&path), code);
+        }
+        if source_map.is_file() {
+            snapshots
+                .source_maps
+                .insert(PathBuf::from(&path), source_map);
+        }
+    }
+    Ok(snapshots)
+}
+
+fn hash_files<'a, P>(files: impl Iterator<Item = &'a P>) -> Fallible<BTreeMap<String, String>>
 where
-    P: AsRef<Path>,
+    P: AsRef<Path> + 'a,
 {
     files
         .map(|p| match hash_file(p) {
@@ -56,24 +368,100 @@ where
         .collect::<Result<BTreeMap<_, _>, _>>()
 }
 
-fn hash_file<P: AsRef<Path>>(file: P) -> Fallible<String> {
+/// Hashes the content of a file
+pub fn hash_file<P: AsRef<Path>>(file: P) -> Fallible<String> {
     let bytes = files::read_file(file.as_ref())?;
     Ok(blake3::hash(&bytes).to_hex().to_string())
 }
 
 /// Content for Yarner.lock files
-#[derive(Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 struct Lock {
+    /// Version of the lock file format
+    version: u32,
+    /// Version of Yarner that wrote the lock file
+    yarner_version: String,
+    /// Hash of the settings the code files were generated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config_hash: Option<String>,
+    /// Source files, including files copied to code output, by path
+    source_files: BTreeMap<String, FileEntry>,
+    /// Code files, by path
+    code_files: BTreeMap<String, FileEntry>,
+}
+
+/// A file recorded in the lock file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FileEntry {
+    /// Hash of the file content
+    hash: String,
+    /// Time the file was generated or played back, in seconds since the Unix epoch
+    generated: u64,
+    /// Source file a code file was produced from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+/// Version of the lock file format, for files of any version
+#[derive(Deserialize)]
+struct LockVersion {
+    /// Version 1 has no version field
+    #[serde(default = "LockVersion::unversioned")]
+    version: u32,
+}
+
+impl LockVersion {
+    fn unversioned() -> u32 {
+        1
+    }
+}
+
+/// Content of Yarner.lock files of version 1
+#[derive(Deserialize)]
+struct LockV1 {
     source_hashes: BTreeMap<String, String>,
     code_hashes: BTreeMap<String, String>,
 }
 
+impl LockV1 {
+    /// Migrates to the current version, with all files generated at the given time
+    fn migrate(self, generated: u64) -> Lock {
+        let entries = |hashes: BTreeMap<String, String>| {
+            hashes
+                .into_iter()
+                .map(|(path, hash)| {
+                    let entry = FileEntry {
+                        hash,
+                        generated,
+                        source: None,
+                    };
+                    (path, entry)
+                })
+                .collect()
+        };
+
+        Lock {
+            version: LOCK_VERSION,
+            source_files: entries(self.source_hashes),
+            code_files: entries(self.code_hashes),
+            ..Lock::default()
+        }
+    }
+}
+
 impl Lock {
     fn read<P: AsRef<Path>>(path: P) -> Fallible<Self> {
         let buf = files::read_file_string(path.as_ref())?;
-        let val = toml::from_str::<Self>(&buf).map_err(|err| {
+
+        // Files of version 1 were written at the time of the last build
+        let modified = fs::metadata(path.as_ref())
+            .and_then(|meta| meta.modified())
+            .map(timestamp)
+            .unwrap_or_default();
+
+        let val = Self::parse(&buf, modified).map_err(|err| {
             format!(
-                "Invalid lock file {}: {}\n  Delete the file or run with option `--force`.",
+                "Invalid lock file {}: {}\n  Delete the file to re-create it with the next build.",
                 path.as_ref().display(),
                 err
             )
@@ -82,6 +470,35 @@ impl Lock {
         Ok(val)
     }
 
+    /// Parses the content of a lock file, and migrates it to the current version if required
+    fn parse(buf: &str, modified: u64) -> Result<Self, String> {
+        let version = toml::from_str::<LockVersion>(buf)
+            .map_err(|err| err.to_string())?
+            .version;
+
+        match version {
+            1 => {
+                info!("Migrating lock file to version {}", LOCK_VERSION);
+                let lock = toml::from_str::<LockV1>(buf).map_err(|err| err.to_string())?;
+                Ok(lock.migrate(modified))
+            }
+            LOCK_VERSION => toml::from_str::<Self>(buf).map_err(|err| err.to_string()),
+            version if version > LOCK_VERSION => Err(format!(
+                "Lock file version {} is not supported by this version of Yarner (up to version {})",
+                version, LOCK_VERSION
+            )),
+            version => Err(format!("Unknown lock file version {}", version)),
+        }
+    }
+
+    /// Hashes of all code files, by path
+    fn code_hashes(&self) -> BTreeMap<String, String> {
+        self.code_files
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.hash.clone()))
+            .collect()
+    }
+
     fn write<P: AsRef<Path>>(&self, path: P) -> Fallible {
         let str = toml::to_string(self)?;
         write(path, str)?;
@@ -89,3 +506,235 @@ impl Lock {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputMode;
+    use crate::testing::{Project, CONFIG};
+
+    #[test]
+    fn migrate_version_1() {
+        let buf = r#"
+[source_hashes]
+"README.md" = "abc"
+
+[code_hashes]
+"code/main.rs" = "def"
+"#;
+        let lock = Lock::parse(buf, 100).unwrap();
+        assert_eq!(lock.version, LOCK_VERSION);
+        assert_eq!(lock.source_files["README.md"].hash, "abc");
+        assert_eq!(
+            lock.code_files["code/main.rs"],
+            FileEntry {
+                hash: "def".to_string(),
+                generated: 100,
+                source: None,
+            }
+        );
+    }
+
+    #[test]
+    fn roundtrip_current_version() {
+        let mut lock = Lock {
+            version: LOCK_VERSION,
+            yarner_version: "0.6.1".to_string(),
+            ..Lock::default()
+        };
+        lock.code_files.insert(
+            "code/main.rs".to_string(),
+            FileEntry {
+                hash: "def".to_string(),
+                generated: 100,
+                source: Some("README.md".to_string()),
+            },
+        );
+
+        let buf = toml::to_string(&lock).unwrap();
+        let parsed = Lock::parse(&buf, 0).unwrap();
+        assert_eq!(parsed.yarner_version, "0.6.1");
+        assert_eq!(parsed.code_files, lock.code_files);
+    }
+
+    /// Creates a temporary project with the given files, and a lock file recording the given code files
+    /// with the content they were generated with. Returns the project and the lock file.
+    fn temp_project(
+        name: &str,
+        files: &[(&str, &str)],
+        generated: &[(&str, &str)],
+    ) -> (Project, PathBuf) {
+        let project = Project::empty(&format!("lock-{}", name));
+        for (path, content) in files {
+            project.write(path, content);
+        }
+        let dir = &project.dir;
+
+        let mut lock = Lock {
+            version: LOCK_VERSION,
+            ..Lock::default()
+        };
+        for (path, content) in generated {
+            lock.code_files.insert(
+                lock_key(&dir.join(path)),
+                FileEntry {
+                    hash: blake3::hash(content.as_bytes()).to_hex().to_string(),
+                    generated: 0,
+                    source: None,
+                },
+            );
+        }
+        let lock_file = dir.join("Yarner.lock");
+        lock.write(&lock_file).unwrap();
+        (project, lock_file)
+    }
+
+    #[test]
+    fn keep_invalid_lock_file() {
+        let (project, lock_file) = temp_project("invalid", &[("a.rs", "a")], &[]);
+        fs::write(&lock_file, "version = ").unwrap();
+
+        let code_files: HashSet<_> = [project.path("a.rs")].into_iter().collect();
+        let output = Output::new(OutputMode::Write);
+        assert!(write_lock(&lock_file, "", &HashSet::new(), &code_files, false, &output).is_err());
+        assert_eq!(fs::read_to_string(&lock_file).unwrap(), "version = ");
+    }
+
+    #[test]
+    fn stale_files_of_previous_build() {
+        let (project, lock_file) = temp_project(
+            "stale",
+            &[("a.rs", "a"), ("b.rs", "b, edited"), ("c.rs", "c")],
+            &[("a.rs", "a"), ("b.rs", "b"), ("c.rs", "c"), ("d.rs", "d")],
+        );
+
+        let current: HashSet<_> = [project.path("a.rs")].into_iter().collect();
+        let mut stale: Vec<_> = stale_files(&lock_file, &current)
+            .unwrap()
+            .into_iter()
+            .map(|file| (file.path, file.modified))
+            .collect();
+        stale.sort();
+
+        // d.rs was already removed
+        assert_eq!(
+            stale,
+            vec![(project.path("b.rs"), true), (project.path("c.rs"), false)]
+        );
+    }
+
+    #[test]
+    fn remove_unmodified_stale_files() {
+        let (project, lock_file) = temp_project(
+            "remove-stale",
+            &[("a.rs", "a"), ("b.rs", "b, edited"), ("c.rs", "c")],
+            &[("a.rs", "a"), ("b.rs", "b"), ("c.rs", "c")],
+        );
+        let current: HashSet<_> = [project.path("a.rs")].into_iter().collect();
+
+        let mut output = Output::new(OutputMode::Write);
+        let kept = remove_stale_files(&lock_file, &current, true, &mut output).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert!(project.path("b.rs").is_file());
+        assert!(project.path("c.rs").is_file());
+
+        let kept = remove_stale_files(&lock_file, &current, false, &mut output).unwrap();
+        assert_eq!(kept, vec![project.path("b.rs")]);
+        assert!(project.path("a.rs").is_file());
+        assert!(project.path("b.rs").is_file());
+        assert!(!project.path("c.rs").exists());
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let buf = format!("version = {}\n", LOCK_VERSION + 1);
+        assert!(Lock::parse(&buf, 0).is_err());
+    }
+
+    const STALE_README: &str = r#"# Stale files
+
+```rust
+//- file:a.rs
+fn a() {}
+```
+
+```rust
+//- file:b.rs
+fn b() {}
+```
+"#;
+
+    const STALE_README_WITHOUT_B: &str = r#"# Stale files
+
+```rust
+//- file:a.rs
+fn a() {}
+```
+"#;
+
+    #[test]
+    fn build_removes_stale_files() {
+        let project = Project::new("stale-remove", STALE_README);
+        project.yarner_ok(&[]);
+        assert!(project.exists("code/b.rs"));
+
+        project.write("README.md", STALE_README_WITHOUT_B);
+        project.yarner_ok(&[]);
+        assert!(project.exists("code/a.rs"));
+        assert!(!project.exists("code/b.rs"));
+    }
+
+    #[test]
+    fn build_keeps_stale_files() {
+        let project = Project::new("stale-keep", STALE_README);
+        project.yarner_ok(&[]);
+
+        project.write("README.md", STALE_README_WITHOUT_B);
+        project.yarner_ok(&["--keep-stale"]);
+        assert!(project.exists("code/b.rs"));
+
+        // The lock still records the kept file
+        project.yarner_ok(&[]);
+        assert!(!project.exists("code/b.rs"));
+    }
+
+    #[test]
+    fn build_keeps_modified_stale_files() {
+        let project = Project::new("stale-modified", STALE_README);
+        project.yarner_ok(&[]);
+
+        project.write("code/b.rs", "fn b() { edited(); }\n");
+        project.write("README.md", STALE_README_WITHOUT_B);
+        project.yarner_ok(&["--force"]);
+        assert_eq!(project.read("code/b.rs"), "fn b() { edited(); }\n");
+
+        let log = project.yarner_ok(&["--force"]);
+        assert!(log.contains("Not removing stale file"));
+        assert!(project.exists("code/b.rs"));
+    }
+
+    #[test]
+    fn changed_config() {
+        let project = Project::new("config-changed", STALE_README_WITHOUT_B);
+        project.yarner_ok(&[]);
+        let code = "// <@README.md#a.rs#0\nfn a() { edited(); }\n// @>README.md#a.rs#0\n";
+        project.write("code/a.rs", code);
+        project.write(
+            "Yarner.toml",
+            &CONFIG.replace("eof_newline = true", "eof_newline = false"),
+        );
+
+        let (result, _) = project.yarner(&["reverse"]);
+        assert!(result.unwrap_err().contains("The config has changed"));
+
+        let log = project.yarner_ok(&["--merge"]);
+        assert!(log.contains("Code changes can't be merged"));
+        assert_eq!(project.read("code/a.rs"), code);
+        // The protected file was generated with the previous config
+        let (result, _) = project.yarner(&["reverse"]);
+        assert!(result.is_err());
+
+        project.yarner_ok(&["--force"]);
+        project.yarner_ok(&["reverse"]);
+    }
+}
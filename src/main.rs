@@ -4,15 +4,26 @@ mod compile;
 mod config;
 mod create;
 mod files;
+mod graph;
+mod lint;
 mod lock;
+mod merge;
+mod output;
 mod parse;
 mod plugin;
 mod print;
+mod review;
+#[cfg(test)]
+mod testing;
 mod util;
+mod verify;
+mod wasm;
 mod watch;
 
 extern crate yarner_lib;
 
+use crate::output::{Output, OutputMode};
+use crate::plugin::PluginHost;
 use crate::util::Fallible;
 use clap::{crate_version, App, Arg, ArgMatches, SubCommand};
 use log::{error, info, LevelFilter};
@@ -27,7 +38,7 @@ fn main() {
         .parse_env("RUST_LOG")
         .init();
 
-    std::process::exit(match run() {
+    std::process::exit(match run(app().get_matches()) {
         Ok(()) => 0,
         Err(err) => {
             error!("{}", err);
@@ -36,7 +47,7 @@ fn main() {
     });
 }
 
-fn get_matches<'a>() -> ArgMatches<'a> {
+fn app<'a, 'b>() -> App<'a, 'b> {
     App::new("Yarner")
         .version(crate_version!())
         .about(r#"Literate Programming tool for Markdown
@@ -95,37 +106,163 @@ The normal workflow is:
             .help("Forces building, although it would result in overwriting changed files.")
             .required(false)
             .takes_value(false))
+        .arg(Arg::with_name("merge")
+            .long("merge")
+            .short("M")
+            .help("Merges changes to Markdown sources and code output, instead of skipping changed files.")
+            .conflicts_with("force")
+            .required(false)
+            .takes_value(false))
+        .arg(Arg::with_name("keep-stale")
+            .long("keep-stale")
+            .help("Only reports code files that are no longer produced, instead of removing them.")
+            .required(false)
+            .takes_value(false))
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Prints the planned file operations instead of writing any files. Works for forward and reverse mode.")
+            .required(false)
+            .takes_value(false))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Prints the plan of option --dry-run as JSON.")
+            .requires("dry-run")
+            .takes_value(false))
         .subcommand(SubCommand::with_name("init")
             .about("Creates a yarner project in the current directory")
         )
         .subcommand(SubCommand::with_name("reverse")
             .about("Reverse mode: play back code changes into source files")
+            .arg(Arg::with_name("unlabeled")
+                .long("unlabeled")
+                .value_name("mode")
+                .help("Handling of code lines outside of block labels: report them, attach them to the adjacent block, or insert them as a new block")
+                .takes_value(true)
+                .possible_values(&["report", "attach", "block"])
+                .default_value("report"))
+            .arg(Arg::with_name("interactive")
+                .long("interactive")
+                .short("i")
+                .help("Shows a diff per changed block, and asks whether to accept, skip or edit the change")
+                .takes_value(false))
+            .arg(Arg::with_name("select")
+                .long("select")
+                .value_name("block")
+                .help("Plays back only changes of the selected blocks, given as 'doc[#name[#index]]'. Non-interactive equivalent of --interactive")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("interactive"))
         )
         .subcommand(SubCommand::with_name("watch")
             .about("Watch files and build project on changes")
         )
-        .get_matches()
+        .subcommand(SubCommand::with_name("clean")
+            .about("Removes code and docs output produced by Yarner")
+            .arg(Arg::with_name("code")
+                .long("code")
+                .help("Removes code output only")
+                .takes_value(false))
+            .arg(Arg::with_name("docs")
+                .long("docs")
+                .help("Removes docs output only")
+                .takes_value(false))
+        )
+        .subcommand(SubCommand::with_name("check")
+            .about("Checks that code and docs output is up to date, without writing any files")
+        )
+        .subcommand(SubCommand::with_name("lint")
+            .about("Checks for unreachable blocks, undefined macros and other problems")
+        )
+        .subcommand(SubCommand::with_name("verify-labels")
+            .about("Checks the block labels in code output for consistency with the Markdown sources")
+        )
+        .subcommand(SubCommand::with_name("expand")
+            .about("Prints a single code block with all macros expanded, without writing any files")
+            .arg(Arg::with_name("block")
+                .help("Name of the code block to expand")
+                .value_name("BLOCK")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("doc")
+                .long("doc")
+                .value_name("file")
+                .help("The document containing the block. Required if the block name is used in multiple documents")
+                .takes_value(true))
+            .arg(Arg::with_name("labels")
+                .long("labels")
+                .help("Includes block labels, if configured for the block's language")
+                .takes_value(false))
+            .arg(Arg::with_name("plugins")
+                .long("plugins")
+                .help("Runs pre-tangle plugins before expanding the block")
+                .takes_value(false))
+        )
+        .subcommand(SubCommand::with_name("graph")
+            .about("Prints the dependency graph of code blocks or documents")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .value_name("format")
+                .help("Output format of the graph")
+                .takes_value(true)
+                .possible_values(&["dot", "mermaid", "json"])
+                .default_value("dot"))
+            .arg(Arg::with_name("documents")
+                .long("documents")
+                .help("Prints the graph of links and transclusions between documents, instead of code blocks")
+                .takes_value(false))
+        )
 }
 
-fn run() -> Fallible {
-    let matches = get_matches();
-
+fn run(matches: ArgMatches) -> Fallible {
     if matches.subcommand_matches("init").is_some() {
         create::create_new_project().map_err(|err| format!("Could not create project: {}", err))?;
         info!("Successfully created project.\nTo compile the project, run 'yarner' from here.",);
         return Ok(());
     }
 
+    if let Some(sub_matches) = matches.subcommand_matches("clean") {
+        return cmd::run_clean(&matches, sub_matches);
+    }
+    if matches.subcommand_matches("check").is_some() {
+        return cmd::run_check(&matches);
+    }
+    if matches.subcommand_matches("lint").is_some() {
+        return cmd::run_lint(&matches);
+    }
+    if matches.subcommand_matches("verify-labels").is_some() {
+        return cmd::run_verify_labels(&matches);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("expand") {
+        return cmd::run_expand(&matches, sub_matches);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("graph") {
+        return cmd::run_graph(&matches, sub_matches);
+    }
+
     let curr_dir = env::current_dir()?;
-    let (config, mut watch_forward, watch_reverse) = cmd::run_with_args(&matches, None, true)?;
+    if matches.is_present("dry-run") {
+        if matches.subcommand_matches("watch").is_some() {
+            return Err("Option --dry-run can't be used with subcommand watch".into());
+        }
+        return cmd::run_dry_run(&matches);
+    }
+
+    let is_watch = matches.subcommand_matches("watch").is_some();
+    let mut plugins = PluginHost::new(is_watch);
+    let mut output = Output::new(OutputMode::Write);
+    let (config, mut watch_forward, watch_reverse) =
+        cmd::run_with_args(&matches, None, true, &mut plugins, &mut output)?;
     env::set_current_dir(&curr_dir)?;
 
-    if matches.subcommand_matches("watch").is_some() {
+    if is_watch {
         watch_forward.insert(config);
         watch::watch(
             matches,
             watch_forward.into_iter(),
             watch_reverse.into_iter(),
+            plugins,
         )?;
     }
 
@@ -0,0 +1,238 @@
+//! Three-way merge of code blocks changed in Markdown sources as well as in code output
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use log::warn;
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use yarner_lib::{Document, Line};
+
+use crate::{
+    code::{BlockKey, RevCodeBlock},
+    config::ParserSettings,
+};
+
+/// Start of the Markdown side of a merge conflict
+pub const CONFLICT_START: &str = "<<<<<<< source";
+/// Separator between the two sides of a merge conflict
+pub const CONFLICT_SEPARATOR: &str = "=======";
+/// End of the code side of a merge conflict
+pub const CONFLICT_END: &str = ">>>>>>> code";
+
+/// Result of a three-way merge
+#[derive(Debug, PartialEq, Eq)]
+pub struct Merged {
+    /// The merged lines, including conflict markers
+    pub lines: Vec<String>,
+    /// Number of conflicting chunks
+    pub conflicts: usize,
+}
+
+/// Merges changes to lines of a block from both sides, relative to a common base.
+/// Conflicting changes are delimited by conflict markers.
+pub fn merge_lines(base: &[String], ours: &[String], theirs: &[String]) -> Merged {
+    let ours_match = match_lines(base, ours);
+    let theirs_match = match_lines(base, theirs);
+
+    let mut lines = vec![];
+    let mut conflicts = 0;
+
+    let (mut i, mut a, mut b) = (0, 0, 0);
+    loop {
+        let mut j = i;
+        while j < base.len() && (ours_match[j].is_none() || theirs_match[j].is_none()) {
+            j += 1;
+        }
+        let (end_a, end_b) = if j < base.len() {
+            (ours_match[j].unwrap(), theirs_match[j].unwrap())
+        } else {
+            (ours.len(), theirs.len())
+        };
+
+        if j == i && end_a == a && end_b == b {
+            if i == base.len() {
+                break;
+            }
+            lines.push(base[i].clone());
+            i += 1;
+            a += 1;
+            b += 1;
+            continue;
+        }
+
+        let (base_chunk, ours_chunk, theirs_chunk) =
+            (&base[i..j], &ours[a..end_a], &theirs[b..end_b]);
+        if ours_chunk == theirs_chunk || theirs_chunk == base_chunk {
+            lines.extend_from_slice(ours_chunk);
+        } else if ours_chunk == base_chunk {
+            lines.extend_from_slice(theirs_chunk);
+        } else {
+            conflicts += 1;
+            lines.push(CONFLICT_START.to_string());
+            lines.extend_from_slice(ours_chunk);
+            lines.push(CONFLICT_SEPARATOR.to_string());
+            lines.extend_from_slice(theirs_chunk);
+            lines.push(CONFLICT_END.to_string());
+        }
+
+        i = j;
+        a = end_a;
+        b = end_b;
+    }
+
+    Merged { lines, conflicts }
+}
+
+/// Merges the blocks of the Markdown sources into the blocks of the code output, using the
+/// blocks of the last generated code as common base. Returns the number of conflicts.
+pub fn merge_blocks(
+    documents: &HashMap<PathBuf, Document>,
+    settings: &ParserSettings,
+    base_blocks: &HashMap<BlockKey, RevCodeBlock>,
+    code_blocks: &mut HashMap<BlockKey, RevCodeBlock>,
+) -> usize {
+    let mut conflicts = 0;
+    for (path, document) in documents {
+        let doc_blocks = document_block_lines(document, settings);
+
+        for (key, block) in code_blocks.iter_mut().filter(|((p, _, _), _)| p == path) {
+            let (_, name, index) = key;
+            let (base, ours) = match (
+                base_blocks.get(key),
+                doc_blocks.get(&(name.clone(), *index)),
+            ) {
+                (Some(base), Some(ours)) => (base, ours),
+                _ => continue,
+            };
+
+            let merged = merge_lines(&base.lines, ours, &block.lines);
+            if merged.conflicts > 0 {
+                warn!(
+                    "Merge conflict in block {} # {} # {}. Resolve the conflict markers in the Markdown source.",
+                    path.display(),
+                    name.as_deref().unwrap_or(""),
+                    index
+                );
+                conflicts += merged.conflicts;
+            }
+            block.lines = merged.lines;
+        }
+    }
+    conflicts
+}
+
+/// For each base line, the index of the matching line in the other version
+fn match_lines(base: &[String], other: &[String]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    for op in capture_diff_slices(Algorithm::Myers, base, other) {
+        if let DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = op
+        {
+            for offset in 0..len {
+                matches[old_index + offset] = Some(new_index + offset);
+            }
+        }
+    }
+    matches
+}
+
+/// Lines of all code blocks of a document, as they appear in code output, mapped by block name and index
+pub fn document_block_lines(
+    document: &Document,
+    settings: &ParserSettings,
+) -> HashMap<(Option<String>, usize), Vec<String>> {
+    let mut block_count: HashMap<&Option<String>, usize> = HashMap::new();
+    let mut blocks = HashMap::new();
+
+    for block in document.code_blocks() {
+        let index = {
+            let count = block_count.entry(&block.name).or_default();
+            *count += 1;
+            *count - 1
+        };
+
+        let lines = block
+            .source
+            .iter()
+            .map(|line| match line {
+                Line::Source { indent, source } => {
+                    if source.trim().is_empty() {
+                        String::new()
+                    } else {
+                        format!("{}{}", indent, source)
+                    }
+                }
+                Line::Macro { indent, name } => format!(
+                    "{}{}{}{}{}",
+                    indent,
+                    settings.macro_start,
+                    if settings.macro_start.ends_with(' ') {
+                        ""
+                    } else {
+                        " "
+                    },
+                    name,
+                    settings.macro_end,
+                ),
+            })
+            .collect();
+
+        blocks.insert((block.name.clone(), index), lines);
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn merge_unchanged() {
+        let base = lines("a\nb\nc");
+        let merged = merge_lines(&base, &base, &base);
+        assert_eq!(merged.lines, base);
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn merge_changes_from_both_sides() {
+        let base = lines("a\nb\nc\nd\ne");
+        let ours = lines("A\nb\nc\nd\ne");
+        let theirs = lines("a\nb\nc\nd\nE\nf");
+
+        let merged = merge_lines(&base, &ours, &theirs);
+        assert_eq!(merged.lines, lines("A\nb\nc\nd\nE\nf"));
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn merge_identical_changes() {
+        let base = lines("a\nb\nc");
+        let changed = lines("a\nB\nc");
+
+        let merged = merge_lines(&base, &changed, &changed);
+        assert_eq!(merged.lines, changed);
+        assert_eq!(merged.conflicts, 0);
+    }
+
+    #[test]
+    fn merge_conflict() {
+        let base = lines("a\nb\nc");
+        let ours = lines("a\nB1\nc");
+        let theirs = lines("a\nB2\nc");
+
+        let merged = merge_lines(&base, &ours, &theirs);
+        assert_eq!(
+            merged.lines,
+            lines("a\n<<<<<<< source\nB1\n=======\nB2\n>>>>>>> code\nc")
+        );
+        assert_eq!(merged.conflicts, 1);
+    }
+}
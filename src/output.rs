@@ -0,0 +1,331 @@
+//! Handling of all files produced by Yarner, either writing, checking or planning them
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use similar::TextDiff;
+
+use crate::{files, util::Fallible};
+
+/// How produced files are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Write files to disk
+    Write,
+    /// Compare files with their state on disk, without writing anything
+    Check,
+    /// Record a plan of file operations, without writing anything
+    DryRun,
+}
+
+/// A planned file operation, in dry-run mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Write a file that does not exist yet
+    Create,
+    /// Overwrite an existing file with different content
+    Update,
+    /// Leave an unchanged file alone
+    Skip,
+    /// Copy a file
+    Copy,
+    /// Remove a file that is no longer produced
+    Remove,
+    /// Leave a file alone that was changed since the last build, or depends on one
+    Protect,
+}
+
+/// A file operation of the plan, in dry-run mode
+#[derive(Debug, Serialize)]
+pub struct PlannedFile {
+    /// The operation
+    pub action: Action,
+    /// Path of the target file
+    pub path: PathBuf,
+    /// Source file, for copy operations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<PathBuf>,
+}
+
+/// A produced file that differs from the file on disk
+#[derive(Debug)]
+pub struct Mismatch {
+    /// Path of the file
+    pub path: PathBuf,
+    /// Unified diff from the file on disk to the produced content
+    pub diff: String,
+}
+
+/// Destination for all files produced by Yarner
+pub struct Output {
+    mode: OutputMode,
+    mismatches: Vec<Mismatch>,
+    plan: Vec<PlannedFile>,
+    source_maps: HashMap<PathBuf, String>,
+    labeled: HashSet<PathBuf>,
+    protected: HashSet<PathBuf>,
+    kept: HashSet<PathBuf>,
+    origins: HashMap<PathBuf, PathBuf>,
+}
+
+impl Output {
+    pub fn new(mode: OutputMode) -> Self {
+        Self {
+            mode,
+            mismatches: vec![],
+            plan: vec![],
+            source_maps: HashMap::new(),
+            labeled: HashSet::new(),
+            protected: HashSet::new(),
+            kept: HashSet::new(),
+            origins: HashMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> OutputMode {
+        self.mode
+    }
+
+    /// Files that differ from their state on disk, in check mode
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    /// Planned file operations, in dry-run mode
+    pub fn plan(&self) -> &[PlannedFile] {
+        &self.plan
+    }
+
+    /// Source maps of code files produced without block labels
+    pub fn source_maps(&self) -> &HashMap<PathBuf, String> {
+        &self.source_maps
+    }
+
+    /// Records the source map of a code file produced without block labels
+    pub fn record_source_map(&mut self, path: &Path, source_map: String) {
+        self.source_maps.insert(path.to_owned(), source_map);
+    }
+
+    /// Code files produced with block labels
+    pub fn labeled(&self) -> &HashSet<PathBuf> {
+        &self.labeled
+    }
+
+    /// Records a code file produced with block labels
+    pub fn record_labeled(&mut self, path: &Path) {
+        self.labeled.insert(path.to_owned());
+    }
+
+    /// Source files of produced files, by produced file
+    pub fn origins(&self) -> &HashMap<PathBuf, PathBuf> {
+        &self.origins
+    }
+
+    /// Records the source file a file is produced from
+    pub fn record_origin(&mut self, path: &Path, origin: &Path) {
+        self.origins.insert(path.to_owned(), origin.to_owned());
+    }
+
+    /// Files that are never written, as they were changed since the last build
+    pub fn protected(&self) -> &HashSet<PathBuf> {
+        &self.protected
+    }
+
+    /// Protects files from being written or copied to
+    pub fn protect<I: IntoIterator<Item = PathBuf>>(&mut self, paths: I) {
+        self.protected.extend(paths);
+    }
+
+    /// Files with content not produced by this build, keeping their entries of the previous lock file
+    pub fn kept(&self) -> &HashSet<PathBuf> {
+        &self.kept
+    }
+
+    /// Keeps the lock entries of files, as their content was not produced by this build
+    pub fn keep<I: IntoIterator<Item = PathBuf>>(&mut self, paths: I) {
+        self.kept.extend(paths);
+    }
+
+    /// Writes a text file if the content differs from the file on disk.
+    /// Returns whether the file differs.
+    pub fn write(&mut self, path: &Path, content: &str) -> Fallible<bool> {
+        if self.protected.contains(path) {
+            self.record(Action::Protect, path, None);
+            return Ok(false);
+        }
+        if !files::file_differs(path, content) {
+            self.record(Action::Skip, path, None);
+            return Ok(false);
+        }
+
+        match self.mode {
+            OutputMode::Write => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, content)?;
+            }
+            OutputMode::Check => {
+                let old = files::read_file_string(path).unwrap_or_default();
+                self.mismatches.push(Mismatch {
+                    path: path.to_owned(),
+                    diff: unified_diff(path, &old, content),
+                });
+            }
+            OutputMode::DryRun => {
+                let action = if path.exists() {
+                    Action::Update
+                } else {
+                    Action::Create
+                };
+                self.record(action, path, None);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Copies a file if the content differs from the target file.
+    /// Returns whether the files differ.
+    pub fn copy(&mut self, from: &Path, to: &Path) -> Fallible<bool> {
+        if self.protected.contains(to) {
+            self.record(Action::Protect, to, Some(from));
+            return Ok(false);
+        }
+        self.record_origin(to, from);
+        if !files::files_differ(from, to) {
+            self.record(Action::Skip, to, Some(from));
+            return Ok(false);
+        }
+
+        match self.mode {
+            OutputMode::Write => {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(from, to)
+                    .map_err(|err| format!("Error copying file {}: {}", from.display(), err))?;
+            }
+            OutputMode::Check => {
+                let new = files::read_file(from)?;
+                let old = files::read_file(to).unwrap_or_default();
+                let diff = match (String::from_utf8(old), String::from_utf8(new)) {
+                    (Ok(old), Ok(new)) => unified_diff(to, &old, &new),
+                    _ => format!("Binary file {} differs\n", to.display()),
+                };
+                self.mismatches.push(Mismatch {
+                    path: to.to_owned(),
+                    diff,
+                });
+            }
+            OutputMode::DryRun => self.record(Action::Copy, to, Some(from)),
+        }
+        Ok(true)
+    }
+
+    /// Removes a file that is no longer produced
+    pub fn remove(&mut self, path: &Path) -> Fallible {
+        match self.mode {
+            OutputMode::Write => fs::remove_file(path)
+                .map_err(|err| format!("Error removing file {}: {}", path.display(), err))?,
+            OutputMode::Check => self.mismatches.push(Mismatch {
+                path: path.to_owned(),
+                diff: format!("Stale file {} is no longer produced\n", path.display()),
+            }),
+            OutputMode::DryRun => self.record(Action::Remove, path, None),
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, action: Action, path: &Path, from: Option<&Path>) {
+        if self.mode == OutputMode::DryRun {
+            self.plan.push(PlannedFile {
+                action,
+                path: path.to_owned(),
+                from: from.map(|from| from.to_owned()),
+            });
+        }
+    }
+
+    /// Formats the plan of file operations, as text or JSON
+    pub fn format_plan(&self, json: bool) -> Fallible<String> {
+        if json {
+            return Ok(serde_json::to_string_pretty(&self.plan)?);
+        }
+
+        let mut out = String::new();
+        for file in &self.plan {
+            let action = match file.action {
+                Action::Create => "create",
+                Action::Update => "update",
+                Action::Skip => "skip",
+                Action::Copy => "copy",
+                Action::Remove => "remove",
+                Action::Protect => "protect",
+            };
+            write!(out, "{:<8}{}", action, file.path.display()).unwrap();
+            if let Some(from) = &file.from {
+                write!(out, " (from {})", from.display()).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+        Ok(out)
+    }
+}
+
+fn unified_diff(path: &Path, old: &str, new: &str) -> String {
+    let path = path.display().to_string();
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&format!("a/{}", path), &format!("b/{}", path))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_of_changed_line() {
+        let diff = unified_diff(Path::new("code/main.rs"), "a\nb\nc\n", "a\nB\nc\n");
+        assert_eq!(
+            diff,
+            "--- a/code/main.rs\n+++ b/code/main.rs\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n"
+        );
+    }
+
+    #[test]
+    fn check_does_not_write() {
+        let path = Path::new("missing-dir/missing-file.txt");
+        let mut output = Output::new(OutputMode::Check);
+        assert!(output.write(path, "content\n").unwrap());
+        assert!(!path.exists());
+        assert_eq!(output.mismatches().len(), 1);
+        assert_eq!(output.mismatches()[0].path, path);
+    }
+
+    #[test]
+    fn dry_run_records_plan() {
+        let path = Path::new("missing-dir/missing-file.txt");
+        let mut output = Output::new(OutputMode::DryRun);
+        assert!(output.write(path, "content\n").unwrap());
+        assert!(!path.exists());
+        assert_eq!(output.plan.len(), 1);
+        assert_eq!(output.plan[0].action, Action::Create);
+        assert_eq!(
+            output.format_plan(false).unwrap(),
+            "create  missing-dir/missing-file.txt\n"
+        );
+    }
+
+    #[test]
+    fn protected_file_not_written() {
+        let path = Path::new("missing-dir/protected-file.txt");
+        let mut output = Output::new(OutputMode::DryRun);
+        output.protect(vec![path.to_owned()]);
+        assert!(!output.write(path, "content\n").unwrap());
+        assert_eq!(output.plan[0].action, Action::Protect);
+    }
+}
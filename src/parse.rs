@@ -185,11 +185,7 @@ fn start_or_extend_text(
     block: Option<&mut TextBlock>,
 ) -> (Option<Node>, Option<Box<dyn Error>>) {
     let parsed = parse_links(line, root_file, path, settings, is_reverse, links);
-    let line = if parsed.is_some() {
-        parsed.as_ref().unwrap()
-    } else {
-        line
-    };
+    let line = parsed.as_deref().unwrap_or(line);
     let mut node = None;
     let mut error = None;
     match parse_transclusion(line, path, settings) {
@@ -722,7 +718,7 @@ text
         assert_eq!(doc.nodes.len(), 3);
         assert_eq!(links.len(), 0);
         assert!(if let Node::Transclusion(trans) = &doc.nodes[1] {
-            trans.file == PathBuf::from("test.md")
+            trans.file == *"test.md"
         } else {
             false
         });
@@ -1,22 +1,127 @@
-use std::collections::HashMap;
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use std::sync::{
+    mpsc::{channel, Receiver, RecvTimeoutError},
+    Arc, Mutex, MutexGuard,
+};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::{info, warn};
-use yarner_lib::{Context, Document, YarnerData, YARNER_VERSION};
+use log::{error, info, warn};
+use serde::Serialize;
+use toml::Value;
+use yarner_lib::{
+    Context, Diagnostic, DiagnosticLevel, Document, ExtraFile, FileContent, OutputTarget,
+    RpcRequest, RpcResponse, Supports, YarnerData, PERSISTENT_ARG, PERSISTENT_CAPABILITY,
+    PROCESS_METHOD, PROTOCOL_VERSION, YARNER_VERSION,
+};
 
 use crate::config::Config;
 use crate::util::Fallible;
+use crate::wasm;
 
-pub fn run_plugins(
-    config: &Config,
-    documents: HashMap<PathBuf, Document>,
-    strict: bool,
-) -> Fallible<HashMap<PathBuf, Document>> {
-    let mut docs = documents;
-    for (name, config) in &config.plugin {
+/// Capabilities offered to plugins, which they can opt into in the handshake
+const CAPABILITIES: &[&str] = &[PERSISTENT_CAPABILITY];
+
+/// Number of recent stderr lines of a persistent plugin, attached to errors of a request
+const MAX_STDERR_LINES: usize = 100;
+
+/// Time to wait for the remaining stderr of a persistent plugin that exited unexpectedly
+const STDERR_GRACE: Duration = Duration::from_secs(1);
+
+/// Stage of the build process in which a plugin runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PluginStage {
+    /// Before code extraction. Changes affect code and documentation output
+    PreTangle,
+    /// After code extraction, only if code is written. Changes affect documentation output
+    PostTangle,
+    /// After code extraction, also when only documentation is rebuilt. Changes affect documentation output
+    Docs,
+}
+
+impl FromStr for PluginStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre-tangle" => Ok(PluginStage::PreTangle),
+            "post-tangle" => Ok(PluginStage::PostTangle),
+            "docs" => Ok(PluginStage::Docs),
+            _ => Err(format!(
+                "Invalid plugin stage '{}'. Must be one of pre-tangle, post-tangle, docs",
+                s
+            )),
+        }
+    }
+}
+
+/// Settings of a plugin, from its section in the config file
+struct PluginSettings<'a> {
+    /// Command to run the plugin
+    command: String,
+    /// Command line arguments
+    arguments: Vec<&'a str>,
+    /// Stage of the build process in which the plugin runs
+    stage: PluginStage,
+    /// Plugins this plugin must run after
+    after: Vec<&'a str>,
+    /// Plugins this plugin must run before
+    before: Vec<&'a str>,
+    /// Whether the plugin only reads the documents, and can run in parallel to other read-only plugins
+    read_only: bool,
+    /// Limits for the plugin process
+    limits: Limits,
+}
+
+/// Limits for a plugin process, beyond which the plugin is killed
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    /// Maximum run time of processes, if limited
+    timeout: Option<Duration>,
+    /// Maximum fuel consumed by WebAssembly plugins, if limited
+    max_fuel: Option<u64>,
+    /// Maximum size of output to stdout and stderr, each, in bytes
+    max_output: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            max_fuel: None,
+            max_output: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl<'a> PluginSettings<'a> {
+    /// The command and its arguments
+    fn command_line(&self) -> Vec<String> {
+        std::iter::once(self.command.as_str())
+            .chain(self.arguments.iter().copied())
+            .map(|arg| arg.to_string())
+            .collect()
+    }
+
+    /// Whether the plugin is a WebAssembly module, run in-process
+    fn is_wasm(&self) -> bool {
+        Path::new(&self.command)
+            .extension()
+            .is_some_and(|ext| ext == "wasm")
+    }
+
+    fn from_config(name: &str, config: &'a Value) -> Fallible<Self> {
+        Ok(Self::parse(name, config)
+            .map_err(|err| format!("Invalid settings for plugin '{}': {}", name, err))?)
+    }
+
+    fn parse(name: &str, config: &'a Value) -> Result<Self, String> {
         let command = config
             .get("command")
             .and_then(|cmd| cmd.as_str().map(|s| s.to_owned()))
@@ -32,92 +137,1167 @@ pub fn run_plugins(
             .transpose()?
             .unwrap_or_default();
 
-        let data = YarnerData {
-            context: Context {
-                name: name.to_owned(),
-                config: config.clone(),
-                yarner_version: YARNER_VERSION.to_string(),
+        let stage = config
+            .get("stage")
+            .map(|stage| {
+                stage
+                    .as_str()
+                    .ok_or_else(|| "Plugin stage must be a string".to_string())
+                    .and_then(|stage| stage.parse())
+            })
+            .transpose()?
+            .unwrap_or(PluginStage::Docs);
+
+        let read_only = config
+            .get("read_only")
+            .map(|read_only| {
+                read_only
+                    .as_bool()
+                    .ok_or("Option read_only must be a boolean")
+            })
+            .transpose()?
+            .unwrap_or(false);
+
+        let default_limits = Limits::default();
+        let timeout = config
+            .get("timeout")
+            .map(|timeout| {
+                timeout
+                    .as_float()
+                    .or_else(|| timeout.as_integer().map(|secs| secs as f64))
+                    .filter(|secs| *secs > 0.0)
+                    .map(Duration::from_secs_f64)
+                    .ok_or("Option timeout must be a positive number of seconds")
+            })
+            .transpose()?
+            .or(default_limits.timeout);
+        let max_output = config
+            .get("max_output")
+            .map(|max_output| {
+                max_output
+                    .as_integer()
+                    .filter(|bytes| *bytes > 0)
+                    .map(|bytes| bytes as usize)
+                    .ok_or("Option max_output must be a positive number of bytes")
+            })
+            .transpose()?
+            .unwrap_or(default_limits.max_output);
+        let max_fuel = config
+            .get("max_fuel")
+            .map(|max_fuel| {
+                max_fuel
+                    .as_integer()
+                    .filter(|fuel| *fuel > 0)
+                    .map(|fuel| fuel as u64)
+                    .ok_or("Option max_fuel must be a positive integer")
+            })
+            .transpose()?
+            .or(default_limits.max_fuel);
+
+        let is_wasm = Path::new(&command)
+            .extension()
+            .is_some_and(|ext| ext == "wasm");
+        if is_wasm && timeout.is_some() {
+            return Err(
+                "Option timeout is not supported for WebAssembly plugins, use max_fuel instead"
+                    .into(),
+            );
+        }
+        if !is_wasm && max_fuel.is_some() {
+            return Err("Option max_fuel is only supported for WebAssembly plugins".into());
+        }
+
+        Ok(Self {
+            command,
+            arguments,
+            stage,
+            after: plugin_names(config, "after")?,
+            before: plugin_names(config, "before")?,
+            read_only,
+            limits: Limits {
+                timeout,
+                max_fuel,
+                max_output,
             },
-            documents: docs,
+        })
+    }
+}
+
+/// Reads an array of plugin names from the plugin's settings
+fn plugin_names<'a>(config: &'a Value, key: &str) -> Result<Vec<&'a str>, String> {
+    match config.get(key) {
+        None => Ok(vec![]),
+        Some(value) => value
+            .as_array()
+            .and_then(|arr| arr.iter().map(|name| name.as_str()).collect())
+            .ok_or_else(|| format!("Option {} must be an array of plugin names", key)),
+    }
+}
+
+/// A plugin from the config file
+struct Plugin<'a> {
+    name: &'a str,
+    config: &'a Value,
+    settings: PluginSettings<'a>,
+}
+
+/// Checks the settings of all plugins, and their order
+pub fn check_plugins(config: &Config) -> Fallible {
+    plugin_order(config).map(|_| ())
+}
+
+/// All plugins in the order of execution.
+/// Plugins are ordered by stage, and by constraints `after` and `before` within a stage.
+/// Otherwise, they keep the order of the config file.
+fn plugin_order(config: &Config) -> Fallible<Vec<Plugin<'_>>> {
+    let plugins = config
+        .plugin
+        .iter()
+        .map(|(name, config)| {
+            Ok(Plugin {
+                name,
+                config,
+                settings: PluginSettings::from_config(name, config)?,
+            })
+        })
+        .collect::<Fallible<Vec<_>>>()?;
+
+    let index: HashMap<&str, usize> = plugins
+        .iter()
+        .enumerate()
+        .map(|(idx, plugin)| (plugin.name, idx))
+        .collect();
+    let find = |name: &str, plugin: &Plugin, key: &str| {
+        index.get(name).copied().ok_or_else(|| {
+            format!(
+                "Invalid settings for plugin '{}': Option {} refers to unknown plugin '{}'",
+                plugin.name, key, name
+            )
+        })
+    };
+
+    // Edges from each plugin to the plugins that must run before it, within the same stage
+    let mut preceding: Vec<HashSet<usize>> = vec![HashSet::new(); plugins.len()];
+    for (idx, plugin) in plugins.iter().enumerate() {
+        let edges = plugin
+            .settings
+            .after
+            .iter()
+            .map(|name| Ok((find(name, plugin, "after")?, idx)))
+            .chain(
+                plugin
+                    .settings
+                    .before
+                    .iter()
+                    .map(|name| Ok((idx, find(name, plugin, "before")?))),
+            )
+            .collect::<Result<Vec<_>, String>>()?;
+
+        for (first, second) in edges {
+            let (first_stage, second_stage) = (
+                plugins[first].settings.stage,
+                plugins[second].settings.stage,
+            );
+            if first_stage > second_stage {
+                return Err(format!(
+                    "Plugin '{}' can't run after plugin '{}', which runs in a later stage",
+                    plugins[second].name, plugins[first].name
+                )
+                .into());
+            }
+            if first_stage == second_stage {
+                preceding[second].insert(first);
+            }
+        }
+    }
+
+    let mut order = vec![];
+    let mut done = vec![false; plugins.len()];
+    while order.len() < plugins.len() {
+        let next = (0..plugins.len())
+            .filter(|idx| !done[*idx] && preceding[*idx].iter().all(|pre| done[*pre]))
+            .min_by_key(|idx| (plugins[*idx].settings.stage, *idx));
+
+        match next {
+            Some(idx) => {
+                done[idx] = true;
+                order.push(idx);
+            }
+            None => {
+                let cycle = find_cycle(&preceding, &done);
+                return Err(format!(
+                    "Cyclic order of plugins: {}",
+                    cycle
+                        .iter()
+                        .map(|idx| format!("'{}'", plugins[*idx].name))
+                        .collect::<Vec<_>>()
+                        .join(" -> ")
+                )
+                .into());
+            }
+        }
+    }
+
+    let mut plugins: Vec<_> = plugins.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .map(|idx| plugins[idx].take().unwrap())
+        .collect())
+}
+
+/// Finds a cycle among the plugins not done yet, where each of them has a preceding plugin not done yet.
+/// The cycle is returned in order of execution, with the first plugin repeated at the end.
+fn find_cycle(preceding: &[HashSet<usize>], done: &[bool]) -> Vec<usize> {
+    let mut path: Vec<usize> = vec![];
+    let mut current = (0..done.len()).find(|idx| !done[*idx]).unwrap();
+    while !path.contains(&current) {
+        path.push(current);
+        current = *preceding[current]
+            .iter()
+            .filter(|pre| !done[**pre])
+            .min()
+            .unwrap();
+    }
+    let start = path.iter().position(|idx| *idx == current).unwrap();
+    let mut cycle = path.split_off(start);
+    cycle.push(current);
+    cycle.reverse();
+    cycle
+}
+
+/// Plugins of the given stage in order of execution, grouped for parallel execution.
+/// Consecutive read-only plugins without ordering constraints among them form a group.
+fn plugin_groups(config: &Config, stage: PluginStage) -> Fallible<Vec<Vec<Plugin<'_>>>> {
+    let mut groups: Vec<Vec<Plugin>> = vec![];
+    for plugin in plugin_order(config)?
+        .into_iter()
+        .filter(|plugin| plugin.settings.stage == stage)
+    {
+        let joins_group = groups.last().is_some_and(|group| {
+            plugin.settings.read_only
+                && group.iter().all(|other| {
+                    other.settings.read_only
+                        && !plugin.settings.after.contains(&other.name)
+                        && !other.settings.before.contains(&plugin.name)
+                })
+        });
+        if joins_group {
+            groups.last_mut().unwrap().push(plugin);
+        } else {
+            groups.push(vec![plugin]);
+        }
+    }
+    Ok(groups)
+}
+
+/// Plugin processes kept running across rebuilds, for plugins with capability `persistent`,
+/// and the handshakes of all plugins
+pub struct PluginHost {
+    /// Whether to keep plugins running. Otherwise, plugins are started anew for each call
+    persistent: bool,
+    sessions: HashMap<String, Session>,
+    /// Answers to the handshake, by plugin name, and the command line they were received from
+    handshakes: HashMap<String, (Vec<String>, Supports)>,
+}
+
+impl PluginHost {
+    /// Creates a host for plugins, which keeps plugins running if `persistent` is `true`
+    pub fn new(persistent: bool) -> Self {
+        Self {
+            persistent,
+            sessions: HashMap::new(),
+            handshakes: HashMap::new(),
+        }
+    }
+
+    /// Takes the cached handshake of a plugin, if it was received from the plugin's current command line
+    fn take_handshake(&mut self, plugin: &Plugin) -> Option<Supports> {
+        self.handshakes
+            .remove(plugin.name)
+            .filter(|(command_line, _)| *command_line == plugin.settings.command_line())
+            .map(|(_, supports)| supports)
+    }
+}
+
+/// Runs all plugins of the given stage on the documents.
+/// Additional files produced by the plugins are added to `extra_files`.
+pub fn run_plugins(
+    config: &Config,
+    documents: HashMap<PathBuf, Document>,
+    extra_files: &mut HashMap<PathBuf, ExtraFile>,
+    stage: PluginStage,
+    strict: bool,
+    host: &mut PluginHost,
+) -> Fallible<HashMap<PathBuf, Document>> {
+    host.sessions
+        .retain(|name, _| config.plugin.contains_key(name));
+    host.handshakes
+        .retain(|name, _| config.plugin.contains_key(name));
+    let persistent = host.persistent;
+    let project = ProjectSettings::from_config(config);
+
+    let mut docs = documents;
+    for group in plugin_groups(config, stage)? {
+        let states: Vec<_> = group
+            .iter()
+            .map(|plugin| PluginState {
+                session: host.sessions.remove(plugin.name),
+                supports: host.take_handshake(plugin),
+            })
+            .collect();
+
+        let results: Vec<_> = if let [plugin] = &group[..] {
+            let mut state = states.into_iter().next().unwrap();
+            let result = call_plugin(plugin, &project, &docs, strict, persistent, &mut state);
+            vec![(result, state)]
+        } else {
+            info!(
+                "Running plugins {} in parallel",
+                group
+                    .iter()
+                    .map(|plugin| format!("'{}'", plugin.name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            let docs = &docs;
+            let project = &project;
+            thread::scope(|scope| {
+                let handles: Vec<_> = group
+                    .iter()
+                    .zip(states)
+                    .map(|(plugin, mut state)| {
+                        scope.spawn(move || {
+                            let result =
+                                call_plugin(plugin, project, docs, strict, persistent, &mut state)
+                                    .map_err(|err| err.to_string());
+                            (result, state)
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| match handle.join() {
+                        Ok((result, state)) => (result.map_err(|err| err.into()), state),
+                        Err(_) => (Err("Plugin thread panicked".into()), PluginState::default()),
+                    })
+                    .collect()
+            })
         };
 
-        let json = to_json(&data)?;
+        let mut first_error = None;
+        for (plugin, (result, state)) in group.iter().zip(results) {
+            if let Some(session) = state.session {
+                host.sessions.insert(plugin.name.to_string(), session);
+            }
+            if let Some(supports) = state.supports {
+                host.handshakes.insert(
+                    plugin.name.to_string(),
+                    (plugin.settings.command_line(), supports),
+                );
+            }
+            match result {
+                Ok(Some(result)) if first_error.is_none() => {
+                    add_extra_files(plugin.name, result.extra_files, extra_files, strict)?;
+                    if plugin.settings.read_only {
+                        check_read_only(plugin.name, &docs, &result.documents, strict)?;
+                    } else {
+                        docs = result.documents;
+                    }
+                }
+                Err(err) if first_error.is_none() => first_error = Some(err),
+                _ => {}
+            }
+        }
+        if let Some(err) = first_error {
+            return Err(err);
+        }
+    }
+    Ok(docs)
+}
+
+/// State of a plugin kept by the host between calls
+#[derive(Default)]
+struct PluginState {
+    /// The running plugin, if persistent
+    session: Option<Session>,
+    /// The plugin's answer to the handshake
+    supports: Option<Supports>,
+}
 
-        info!("Running plugin '{}'", name);
+/// Settings of the project, passed to plugins in the context
+struct ProjectSettings {
+    parser: yarner_lib::ParserSettings,
+    paths: yarner_lib::Paths,
+    language: HashMap<String, yarner_lib::LanguageSettings>,
+}
 
-        let mut child = Command::new(&command)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .args(&arguments)
-            .spawn()
-            .map_err(|err| format_error(err.into(), &command))?;
+impl ProjectSettings {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            parser: (&config.parser).into(),
+            paths: (&config.paths).into(),
+            language: config
+                .language
+                .iter()
+                .map(|(name, settings)| (name.clone(), settings.into()))
+                .collect(),
+        }
+    }
 
-        let has_input = if let Err(err) = child
-            .stdin
-            .as_mut()
-            .ok_or_else(|| "No stdin available.".to_string())
-            .and_then(|stdin| {
-                stdin
-                    .write_all(json.as_bytes())
-                    .map_err(|err| err.to_string())
-            }) {
+    /// Whether the context was created with these settings
+    fn matches(&self, context: &Context) -> bool {
+        context.parser.as_ref() == Some(&self.parser)
+            && context.paths.as_ref() == Some(&self.paths)
+            && context.language == self.language
+    }
+}
+
+/// Data sent to a plugin, with documents borrowed
+#[derive(Serialize)]
+struct PluginInput<'a, D> {
+    context: &'a Context,
+    documents: D,
+}
+
+/// Parameters of a request to a persistent plugin, with documents borrowed
+#[derive(Serialize)]
+struct ProcessInput<'a> {
+    data: PluginInput<'a, &'a HashMap<&'a PathBuf, &'a Document>>,
+    removed: &'a [PathBuf],
+}
+
+/// Reports a failed plugin. Returns an error if `strict` is `true`, and `None` otherwise
+fn plugin_failure(message: String, strict: bool) -> Fallible<Option<YarnerData>> {
+    if strict {
+        Err(message.into())
+    } else {
+        warn!("{}", message);
+        Ok(None)
+    }
+}
+
+/// Runs a plugin on the documents, and returns its output.
+/// Returns `None` if the plugin was skipped or failed, and `strict` is `false`.
+///
+/// The plugin's answer to the handshake is stored in `state`.
+/// If `persistent` is `true` and the plugin opted into it in a previous call, the plugin is kept running in `state`.
+fn call_plugin(
+    plugin: &Plugin,
+    project: &ProjectSettings,
+    documents: &HashMap<PathBuf, Document>,
+    strict: bool,
+    persistent: bool,
+    state: &mut PluginState,
+) -> Fallible<Option<YarnerData>> {
+    let Plugin {
+        name,
+        config,
+        settings,
+    } = plugin;
+    let session = &mut state.session;
+    // Restart the plugin if its settings or the project settings have changed
+    if session.as_ref().is_some_and(|session| {
+        session.context.config != **config || !project.matches(&session.context)
+    }) {
+        *session = None;
+    }
+
+    if session.is_none() {
+        let context = Context {
+            name: name.to_string(),
+            config: (*config).clone(),
+            yarner_version: YARNER_VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|cap| cap.to_string()).collect(),
+            parser: Some(project.parser.clone()),
+            paths: Some(project.paths.clone()),
+            language: project.language.clone(),
+        };
+
+        if !persistent
+            || settings.is_wasm()
+            || !state.supports.as_ref().is_some_and(|supports| {
+                supports
+                    .capabilities
+                    .iter()
+                    .any(|cap| cap == PERSISTENT_CAPABILITY)
+            })
+        {
+            let result = match call_once(plugin, &context, documents, strict)? {
+                Some(result) => result,
+                None => return Ok(None),
+            };
+            let supports = result.supports.clone().unwrap_or(Supports {
+                protocol_version: 1,
+                capabilities: vec![],
+            });
+            if supports.protocol_version > PROTOCOL_VERSION {
+                return plugin_failure(format!(
+                    "Plugin '{}' requires plugin protocol version {}, but Yarner {} supports up to version {}.\n  \
+                    Update Yarner, or use an older version of the plugin.",
+                    name, supports.protocol_version, YARNER_VERSION, PROTOCOL_VERSION
+                ), strict);
+            }
+            state.supports = Some(supports);
+            return Ok(Some(result));
+        }
+
+        info!("Starting persistent plugin '{}'", name);
+        match Session::start(plugin, context) {
+            Ok(started) => *session = Some(started),
+            Err(message) => return plugin_failure(message, strict),
+        }
+    }
+
+    info!("Running plugin '{}'", name);
+    let result = match session
+        .as_mut()
+        .unwrap()
+        .process(documents, &settings.limits)
+    {
+        Ok(result) => result,
+        Err(message) => {
+            *session = None;
+            return plugin_failure(message, strict);
+        }
+    };
+    check_diagnostics(name, &result, "", strict)?;
+    Ok(Some(result))
+}
+
+/// Runs a plugin process on the documents, and returns its output
+fn call_once(
+    plugin: &Plugin,
+    context: &Context,
+    documents: &HashMap<PathBuf, Document>,
+    strict: bool,
+) -> Fallible<Option<YarnerData>> {
+    let Plugin { name, settings, .. } = plugin;
+
+    let json = to_json(&PluginInput { context, documents })?;
+
+    info!("Running plugin '{}'", name);
+
+    let output = match execute(name, settings, &[], json.into_bytes())? {
+        Ok(output) => output,
+        Err(message) => return plugin_failure(message, strict),
+    };
+    let has_input = match &output.input_error {
+        Some(err) => {
             warn!(
                 "Plugin '{}' is unable to access child process stdin: {}",
-                name,
-                err.to_string()
+                name, err
             );
-
             false
+        }
+        None => true,
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let result = from_json(&String::from_utf8_lossy(&output.stdout));
+
+    if let Ok(result) = &result {
+        check_diagnostics(name, result, &stderr, strict)?;
+    }
+
+    let failure = if output.exit_code != Some(0) {
+        Some(format!(
+            "Plugin '{}' exits with error {}.",
+            name,
+            output.exit_code.unwrap_or(1)
+        ))
+    } else if !has_input {
+        None
+    } else {
+        result
+            .as_ref()
+            .err()
+            .map(|err| format!("Invalid output from plugin '{}': {}", name, err))
+    };
+
+    match (failure, result) {
+        (Some(message), _) => plugin_failure(with_stderr(message, &stderr), strict),
+        (None, Ok(result)) if has_input => {
+            log_stderr(name, &stderr);
+            Ok(Some(result))
+        }
+        (None, _) => {
+            log_stderr(name, &stderr);
+            if !output.stdout.is_empty() {
+                info!("{}", String::from_utf8_lossy(&output.stdout));
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Reports the diagnostics of a plugin. Fails if the plugin reported errors, and `strict` is `true`
+fn check_diagnostics(name: &str, result: &YarnerData, stderr: &str, strict: bool) -> Fallible {
+    let errors = report_diagnostics(name, &result.diagnostics);
+    if errors > 0 {
+        let message = format!("Plugin '{}' reported {} error(s)", name, errors);
+        if strict {
+            return Err(with_stderr(message, stderr).into());
         } else {
-            true
-        };
+            warn!("{}", message);
+        }
+    }
+    Ok(())
+}
 
-        let output = child
-            .wait_with_output()
-            .map_err(|err| format_error(err.into(), &command))?;
+/// A plugin process kept running across rebuilds, communicating via newline-delimited JSON-RPC
+struct Session {
+    child: Child,
+    /// Stdin of the process, taken while a request is written
+    stdin: Option<ChildStdin>,
+    /// Lines of output of the process
+    responses: Receiver<Result<Vec<u8>, String>>,
+    /// Recent lines of stderr of the process, since the start of the last request
+    stderr: Arc<Mutex<VecDeque<String>>>,
+    /// Disconnected when stderr of the process is closed
+    stderr_closed: Receiver<()>,
+    /// Context sent with each request
+    context: Context,
+    /// Id of the last request
+    last_id: u64,
+    /// Hashes of the documents as last sent to the plugin
+    sent: HashMap<PathBuf, u64>,
+    /// Last output of the plugin, by document
+    outputs: HashMap<PathBuf, Document>,
+}
 
-        docs = if output.status.success() {
-            if has_input {
-                let out_json = String::from_utf8(output.stdout)
-                    .map_err(|err| format_error(err.into(), &command))?;
+impl Session {
+    fn start(plugin: &Plugin, context: Context) -> Result<Self, String> {
+        let PluginSettings {
+            command,
+            arguments,
+            limits,
+            ..
+        } = &plugin.settings;
 
-                match from_json(&out_json) {
-                    Ok(context) => context.documents,
-                    Err(err) => {
-                        warn!("Invalid output from plugin '{}': {}", name, err);
-                        data.documents
+        let mut child = Command::new(command)
+            .args(arguments)
+            .arg(PERSISTENT_ARG)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format_error(err.into(), command))?;
+
+        let name = plugin.name.to_string();
+        let max_output = limits.max_output;
+        let (tx, responses) = channel();
+        if let Some(stdout) = child.stdout.take() {
+            let name = name.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stdout);
+                loop {
+                    let mut line = vec![];
+                    let line = match (&mut reader)
+                        .take(max_output as u64 + 1)
+                        .read_until(b'\n', &mut line)
+                    {
+                        Ok(0) => break,
+                        Ok(_) if line.len() > max_output => Err(format!(
+                            "Plugin '{}' exceeds the maximum output size of {} bytes, and was stopped.",
+                            name, max_output
+                        )),
+                        Ok(_) => Ok(line),
+                        Err(err) => Err(format!("Unable to read output of plugin '{}': {}", name, err)),
+                    };
+                    let stop = line.is_err();
+                    if tx.send(line).is_err() || stop {
+                        break;
+                    }
+                }
+            });
+        }
+        let stderr_lines = Arc::new(Mutex::new(VecDeque::new()));
+        let (closed, stderr_closed) = channel::<()>();
+        if let Some(stderr) = child.stderr.take() {
+            let lines = stderr_lines.clone();
+            thread::spawn(move || {
+                let _closed = closed;
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    info!("Plugin '{}': {}", name, line);
+                    let mut lines = lines.lock().unwrap_or_else(|err| err.into_inner());
+                    if lines.len() == MAX_STDERR_LINES {
+                        lines.pop_front();
                     }
+                    lines.push_back(line);
                 }
+            });
+        }
+
+        Ok(Self {
+            stdin: child.stdin.take(),
+            child,
+            responses,
+            stderr: stderr_lines,
+            stderr_closed,
+            context,
+            last_id: 0,
+            sent: HashMap::new(),
+            outputs: HashMap::new(),
+        })
+    }
+
+    /// Sends the documents changed since the last request to the plugin, and returns its output for all documents.
+    /// Errors include the plugin's stderr since the start of the request.
+    fn process(
+        &mut self,
+        documents: &HashMap<PathBuf, Document>,
+        limits: &Limits,
+    ) -> Result<YarnerData, String> {
+        self.stderr_lines().clear();
+        self.request(documents, limits).map_err(|message| {
+            let stderr = self.stderr_lines().iter().cloned().collect::<Vec<_>>();
+            with_stderr(message, &stderr.join("\n"))
+        })
+    }
+
+    fn stderr_lines(&self) -> MutexGuard<'_, VecDeque<String>> {
+        self.stderr.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn request(
+        &mut self,
+        documents: &HashMap<PathBuf, Document>,
+        limits: &Limits,
+    ) -> Result<YarnerData, String> {
+        let hashes = documents
+            .iter()
+            .map(|(path, doc)| Ok((path, hash_document(doc)?)))
+            .collect::<Result<HashMap<_, _>, String>>()?;
+        let changed: HashMap<&PathBuf, &Document> = documents
+            .iter()
+            .filter(|(path, _)| self.sent.get(*path) != Some(&hashes[path]))
+            .collect();
+        let removed: Vec<PathBuf> = self
+            .sent
+            .keys()
+            .filter(|path| !documents.contains_key(*path))
+            .cloned()
+            .collect();
+
+        self.last_id += 1;
+        let request = RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            id: self.last_id,
+            method: PROCESS_METHOD.to_string(),
+            params: ProcessInput {
+                data: PluginInput {
+                    context: &self.context,
+                    documents: &changed,
+                },
+                removed: &removed,
+            },
+        };
+        let mut line = serde_json::to_vec(&request).map_err(|err| err.to_string())?;
+        line.push(b'\n');
+
+        // Written in a thread, as the plugin may not read its input
+        let mut stdin = self
+            .stdin
+            .take()
+            .ok_or_else(|| format!("Plugin '{}' has no stdin available", self.context.name))?;
+        let writer = thread::spawn(move || {
+            stdin
+                .write_all(&line)
+                .and_then(|_| stdin.flush())
+                .map(|_| stdin)
+        });
+
+        let mut result = self.receive(limits.timeout)?;
+        self.stdin = Some(
+            writer
+                .join()
+                .map_err(|_| "Plugin thread panicked".to_string())?
+                .map_err(|err| {
+                    format!("Unable to write to plugin '{}': {}", self.context.name, err)
+                })?,
+        );
+
+        let mut returned = std::mem::take(&mut result.documents);
+        for path in &removed {
+            self.sent.remove(path);
+            self.outputs.remove(path);
+        }
+        for (path, doc) in changed {
+            self.sent.insert(path.clone(), hashes[path]);
+            self.outputs.insert(
+                path.clone(),
+                returned.remove(path).unwrap_or_else(|| doc.clone()),
+            );
+        }
+
+        let mut added = HashMap::new();
+        for (path, doc) in returned {
+            if documents.contains_key(&path) {
+                self.outputs.insert(path, doc);
             } else {
-                if !output.stdout.is_empty() {
-                    info!("{}", String::from_utf8(output.stdout)?);
+                added.insert(path, doc);
+            }
+        }
+
+        result.documents = documents
+            .keys()
+            .map(|path| (path.clone(), self.outputs[path].clone()))
+            .chain(added)
+            .collect();
+        Ok(result)
+    }
+
+    /// Waits for the response to the last request, for at most `timeout` if given
+    fn receive(&self, timeout: Option<Duration>) -> Result<YarnerData, String> {
+        let name = &self.context.name;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let line = match deadline {
+                Some(deadline) => self
+                    .responses
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self
+                    .responses
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let line = match line {
+                Ok(line) => line?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!(
+                        "Plugin '{}' did not finish within {} seconds, and was stopped.",
+                        name,
+                        timeout.unwrap_or_default().as_secs_f64()
+                    ))
                 }
-                data.documents
+                Err(RecvTimeoutError::Disconnected) => {
+                    // Let the reader thread collect the last lines of stderr
+                    let _ = self.stderr_closed.recv_timeout(STDERR_GRACE);
+                    return Err(format!("Persistent plugin '{}' exited unexpectedly.", name));
+                }
+            };
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
             }
-        } else {
-            if !output.stdout.is_empty() {
-                info!("{}", String::from_utf8(output.stdout)?);
+
+            let response: RpcResponse<YarnerData> = serde_json::from_slice(&line)
+                .map_err(|err| format!("Invalid output from plugin '{}': {}", name, err))?;
+            if response.id.is_some_and(|id| id != self.last_id) {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(format!(
+                    "Plugin '{}' reports error {}: {}",
+                    name, error.code, error.message
+                ));
             }
+            return response
+                .result
+                .ok_or_else(|| format!("Invalid output from plugin '{}': No result", name));
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Closing stdin ends the plugin's request loop, killing it makes sure it stops
+        self.stdin = None;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Hash of a document, to detect changes between requests to persistent plugins
+fn hash_document(document: &Document) -> Result<u64, String> {
+    let json = serde_json::to_string(document).map_err(|err| err.to_string())?;
+    let mut hasher = DefaultHasher::new();
+    json.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+/// Logs the diagnostics reported by a plugin, and returns the number of errors
+fn report_diagnostics(name: &str, diagnostics: &[Diagnostic]) -> usize {
+    let mut errors = 0;
+    for diagnostic in diagnostics {
+        match diagnostic.level {
+            DiagnosticLevel::Warning => warn!("Plugin '{}': {}", name, diagnostic),
+            DiagnosticLevel::Error => {
+                error!("Plugin '{}': {}", name, diagnostic);
+                errors += 1;
+            }
+        }
+    }
+    errors
+}
 
+/// Appends the captured stderr of a plugin to an error message
+fn with_stderr(message: String, stderr: &str) -> String {
+    if stderr.trim().is_empty() {
+        message
+    } else {
+        format!(
+            "{}\n  Plugin stderr:\n{}",
+            message,
+            stderr
+                .trim_end()
+                .lines()
+                .map(|line| format!("    {}", line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+}
+
+/// Logs the captured stderr of a successful plugin run
+fn log_stderr(name: &str, stderr: &str) {
+    for line in stderr.trim_end().lines() {
+        info!("Plugin '{}': {}", name, line);
+    }
+}
+
+/// Reports documents changed or added by a read-only plugin, as these changes are dropped.
+/// Fails if `strict` is `true`. Documents missing in the output are not considered changed.
+fn check_read_only(
+    name: &str,
+    input: &HashMap<PathBuf, Document>,
+    output: &HashMap<PathBuf, Document>,
+    strict: bool,
+) -> Fallible {
+    let mut changed = vec![];
+    for (path, doc) in output {
+        let unchanged = match input.get(path) {
+            Some(original) => hash_document(original)? == hash_document(doc)?,
+            None => false,
+        };
+        if !unchanged {
+            changed.push(path);
+        }
+    }
+    if changed.is_empty() {
+        return Ok(());
+    }
+    changed.sort();
+
+    let message = format!(
+        "Read-only plugin '{}' changes documents, which is ignored: {}\n  \
+        Remove option read_only if the plugin is meant to change documents.",
+        name,
+        changed
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if strict {
+        Err(message.into())
+    } else {
+        warn!("{}", message);
+        Ok(())
+    }
+}
+
+/// Checks the additional files produced by a plugin, and adds them to `extra_files`
+fn add_extra_files(
+    name: &str,
+    files: HashMap<PathBuf, ExtraFile>,
+    extra_files: &mut HashMap<PathBuf, ExtraFile>,
+    strict: bool,
+) -> Fallible {
+    for (path, file) in files {
+        if let Err(err) = check_extra_file(&path, &file) {
             let message = format!(
-                "Plugin '{}' exits with error {}.",
+                "Invalid extra file {} from plugin '{}': {}",
+                path.display(),
                 name,
-                output.status.code().unwrap_or(1)
+                err
             );
-
             if strict {
                 return Err(message.into());
             } else {
                 warn!("{}", message);
+                continue;
             }
+        }
 
-            data.documents
+        info!("Plugin '{}' adds file {}", name, path.display());
+        if extra_files.insert(path.clone(), file).is_some() {
+            warn!(
+                "Plugin '{}' replaces extra file {} of a previous plugin",
+                name,
+                path.display()
+            );
         }
     }
-    Ok(docs)
+    Ok(())
+}
+
+fn check_extra_file(path: &Path, file: &ExtraFile) -> Result<(), &'static str> {
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|comp| matches!(comp, Component::Normal(_)))
+    {
+        return Err("path must be relative to the output directory, without '..'");
+    }
+    if let (OutputTarget::Code, FileContent::Document(_)) = (file.target, &file.content) {
+        return Err("documents can only be written to the documentation output");
+    }
+    Ok(())
+}
+
+/// Output of a plugin process
+struct ProcessOutput {
+    /// Exit code, or `None` if the process was terminated by a signal or trapped
+    exit_code: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// Error writing the input to stdin, e.g. if the process exits before reading all input
+    input_error: Option<String>,
+}
+
+/// Runs a plugin's process or WebAssembly module with the given additional arguments and input to stdin.
+/// Returns an error message if the plugin was stopped due to exceeding its limits.
+fn execute(
+    name: &str,
+    settings: &PluginSettings,
+    extra_arguments: &[&str],
+    input: Vec<u8>,
+) -> Fallible<Result<ProcessOutput, String>> {
+    let mut arguments = settings.arguments.clone();
+    arguments.extend(extra_arguments);
+
+    if !settings.is_wasm() {
+        return run_process(name, &settings.command, &arguments, input, &settings.limits);
+    }
+
+    let output = match wasm::run_wasm(
+        name,
+        Path::new(&settings.command),
+        &arguments,
+        input,
+        settings.limits.max_fuel,
+        settings.limits.max_output,
+    )? {
+        Ok(output) => output,
+        Err(message) => return Ok(Err(message)),
+    };
+    Ok(Ok(ProcessOutput {
+        exit_code: output.exit_code,
+        stdout: output.stdout,
+        stderr: output.stderr,
+        input_error: None,
+    }))
+}
+
+/// Runs a plugin process with the given input to stdin.
+/// Stdin is written, and stdout and stderr are read, in separate threads,
+/// so that a process writing output before it has read all input does not block.
+/// Returns an error message if the process was killed due to exceeding its limits.
+fn run_process(
+    name: &str,
+    command: &str,
+    arguments: &[&str],
+    input: Vec<u8>,
+    limits: &Limits,
+) -> Fallible<Result<ProcessOutput, String>> {
+    let mut child = Command::new(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(arguments)
+        .spawn()
+        .map_err(|err| format_error(err.into(), command))?;
+
+    let max_output = limits.max_output;
+    let mut stdin = child.stdin.take();
+    let writer = thread::spawn(move || match stdin.as_mut() {
+        // Stdin is closed when dropped at the end of the thread
+        Some(stdin) => stdin.write_all(&input).map_err(|err| err.to_string()),
+        None => Err("No stdin available.".to_string()),
+    });
+
+    // Readers send the complete output of their stream, flagged if it exceeds the maximum size
+    let (sender, receiver) = channel();
+    let mut open_streams = 0;
+    if let Some(stdout) = child.stdout.take() {
+        let sender = sender.clone();
+        thread::spawn(move || sender.send((false, read_limited(stdout, max_output))));
+        open_streams += 1;
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let sender = sender.clone();
+        thread::spawn(move || sender.send((true, read_limited(stderr, max_output))));
+        open_streams += 1;
+    }
+    drop(sender);
+
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    while open_streams > 0 {
+        let received = match deadline {
+            Some(deadline) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let message = match received {
+            Ok((is_stderr, output)) => match output? {
+                (_, true) => format!(
+                    "Plugin '{}' exceeds the maximum output size of {} bytes, and was stopped.",
+                    name, limits.max_output
+                ),
+                (output, false) => {
+                    if is_stderr {
+                        stderr = output;
+                    } else {
+                        stdout = output;
+                    }
+                    open_streams -= 1;
+                    continue;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => format!(
+                "Plugin '{}' did not finish within {} seconds, and was stopped.",
+                name,
+                limits.timeout.unwrap_or_default().as_secs_f64()
+            ),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(format!("Unable to read output of plugin '{}'", name).into())
+            }
+        };
+
+        // Reader threads are not joined, as child processes of the plugin may still hold its output open
+        child.kill()?;
+        child.wait()?;
+        return Ok(Err(message));
+    }
+
+    // The plugin has closed its output, so it is about to exit
+    let status = child.wait()?;
+
+    Ok(Ok(ProcessOutput {
+        exit_code: status.code(),
+        stdout,
+        stderr,
+        input_error: writer
+            .join()
+            .map_err(|_| format!("Unable to write input of plugin '{}'", name))?
+            .err(),
+    }))
+}
+
+/// Reads up to `max` bytes. If there is more to read, stops reading,
+/// and returns `true` along with the bytes read.
+fn read_limited<R: Read>(reader: R, max: usize) -> io::Result<(Vec<u8>, bool)> {
+    let mut buffer = vec![];
+    reader.take(max as u64 + 1).read_to_end(&mut buffer)?;
+    let exceeded = buffer.len() > max;
+    buffer.truncate(max);
+    Ok((buffer, exceeded))
 }
 
-fn to_json(data: &YarnerData) -> serde_json::Result<String> {
+fn to_json<D: Serialize>(data: &PluginInput<D>) -> serde_json::Result<String> {
     serde_json::to_string_pretty(&data)
 }
 
@@ -128,3 +1308,373 @@ fn from_json(json: &str) -> serde_json::Result<YarnerData> {
 fn format_error(err: Box<dyn Error>, name: &str) -> String {
     format!("Failed to run plugin command '{}': {}", name, err)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Project;
+
+    #[test]
+    fn extra_files() {
+        let json = r#"{
+            "context": { "config": {}, "name": "test", "yarner_version": "0.0.0" },
+            "documents": {},
+            "extra_files": {
+                "index.txt": { "target": "docs", "content": { "text": "Index" } },
+                "../out.txt": { "target": "code", "content": { "text": "Out" } },
+                "doc.md": { "target": "code", "content": { "document": { "newline": "\n", "nodes": [] } } }
+            }
+        }"#;
+        let mut data = from_json(json).unwrap();
+        assert_eq!(data.extra_files.len(), 3);
+
+        let index = data.extra_files.remove(Path::new("index.txt")).unwrap();
+        assert_eq!(index.target, OutputTarget::Docs);
+        assert!(check_extra_file(Path::new("index.txt"), &index).is_ok());
+        assert!(check_extra_file(Path::new("/index.txt"), &index).is_err());
+
+        for (path, file) in &data.extra_files {
+            assert!(check_extra_file(path, file).is_err());
+        }
+
+        let mut extra_files = HashMap::new();
+        assert!(add_extra_files("test", data.extra_files, &mut extra_files, true).is_err());
+        assert!(extra_files.is_empty());
+    }
+
+    #[test]
+    fn read_only_changes() {
+        let doc = |newline: &str| Document::new(vec![], newline.to_string());
+        let input: HashMap<_, _> = [(PathBuf::from("a.md"), doc("\n"))].into_iter().collect();
+
+        assert!(check_read_only("test", &input, &input.clone(), true).is_ok());
+        assert!(check_read_only("test", &input, &HashMap::new(), true).is_ok());
+
+        let changed: HashMap<_, _> = [(PathBuf::from("a.md"), doc("\r\n"))].into_iter().collect();
+        assert!(check_read_only("test", &input, &changed, true).is_err());
+        assert!(check_read_only("test", &input, &changed, false).is_ok());
+
+        let added: HashMap<_, _> = [(PathBuf::from("b.md"), doc("\n"))].into_iter().collect();
+        assert!(check_read_only("test", &input, &added, true).is_err());
+    }
+
+    #[test]
+    fn diagnostics() {
+        let json = r#"{
+            "context": { "config": {}, "name": "test", "yarner_version": "0.0.0" },
+            "documents": {},
+            "diagnostics": [
+                { "level": "warning", "message": "Undefined term", "path": "README.md", "line": 12 },
+                { "level": "error", "message": "Broken link", "path": "README.md" },
+                { "level": "error", "message": "Failed" }
+            ]
+        }"#;
+        let data = from_json(json).unwrap();
+        assert_eq!(report_diagnostics("test", &data.diagnostics), 2);
+
+        let messages: Vec<_> = data.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "README.md:12: Undefined term",
+                "README.md: Broken link",
+                "Failed"
+            ]
+        );
+
+        assert_eq!(with_stderr("Failed".to_string(), " \n"), "Failed");
+        assert_eq!(
+            with_stderr("Failed".to_string(), "a\nb\n"),
+            "Failed\n  Plugin stderr:\n    a\n    b"
+        );
+    }
+
+    fn config(plugins: &str) -> Config {
+        toml::from_str(&format!(
+            "{}\n{}",
+            include_str!("create/Yarner.toml"),
+            plugins
+        ))
+        .unwrap()
+    }
+
+    fn names(config: &Config) -> Fallible<Vec<&str>> {
+        Ok(plugin_order(config)?
+            .iter()
+            .map(|plugin| plugin.name)
+            .collect())
+    }
+
+    #[test]
+    fn plugin_order_constraints() {
+        let config = config(
+            r#"
+            [plugin.toc]
+            after = ["links"]
+            [plugin.links]
+            [plugin.bib]
+            before = ["links"]
+            [plugin.code]
+            stage = "pre-tangle"
+            "#,
+        );
+        assert_eq!(names(&config).unwrap(), vec!["code", "bib", "links", "toc"]);
+    }
+
+    #[test]
+    fn plugin_order_errors() {
+        let cyclic = config(
+            r#"
+            [plugin.a]
+            after = ["c"]
+            [plugin.b]
+            after = ["a"]
+            [plugin.c]
+            after = ["b"]
+            "#,
+        );
+        assert_eq!(
+            names(&cyclic).unwrap_err().to_string(),
+            "Cyclic order of plugins: 'a' -> 'b' -> 'c' -> 'a'"
+        );
+
+        let unknown = config(
+            r#"
+            [plugin.a]
+            before = ["b"]
+            "#,
+        );
+        assert!(names(&unknown)
+            .unwrap_err()
+            .to_string()
+            .ends_with("refers to unknown plugin 'b'"));
+
+        let stages = config(
+            r#"
+            [plugin.a]
+            before = ["b"]
+            [plugin.b]
+            stage = "pre-tangle"
+            "#,
+        );
+        assert!(names(&stages).is_err());
+    }
+
+    #[test]
+    fn plugin_groups_read_only() {
+        let config = config(
+            r#"
+            [plugin.toc]
+            [plugin.bib]
+            read_only = true
+            [plugin.links]
+            read_only = true
+            [plugin.index]
+            read_only = true
+            after = ["links"]
+            "#,
+        );
+        let groups: Vec<Vec<_>> = plugin_groups(&config, PluginStage::Docs)
+            .unwrap()
+            .iter()
+            .map(|group| group.iter().map(|plugin| plugin.name).collect())
+            .collect();
+        assert_eq!(
+            groups,
+            vec![vec!["toc"], vec!["bib", "links"], vec!["index"]]
+        );
+    }
+
+    #[test]
+    fn plugin_limits() {
+        let config = config(
+            r#"
+            [plugin.a]
+            timeout = 2.5
+            max_output = 1000
+            [plugin.b]
+            [plugin.c]
+            command = "c.wasm"
+            max_fuel = 1_000_000
+            "#,
+        );
+        let plugins = plugin_order(&config).unwrap();
+        assert_eq!(
+            plugins[0].settings.limits.timeout,
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(plugins[0].settings.limits.max_output, 1000);
+        assert_eq!(plugins[1].settings.limits.timeout, None);
+        assert_eq!(plugins[2].settings.limits.max_fuel, Some(1_000_000));
+
+        for invalid in &[
+            "timeout = -1",
+            "max_fuel = 1000",
+            "command = \"a.wasm\"\ntimeout = 1",
+        ] {
+            let invalid = self::config(&format!("[plugin.a]\n{}", invalid));
+            assert!(plugin_order(&invalid).is_err());
+        }
+
+        let (output, exceeded) = read_limited(&b"0123456789"[..], 10).unwrap();
+        assert_eq!(output.len(), 10);
+        assert!(!exceeded);
+
+        let (output, exceeded) = read_limited(&b"0123456789"[..], 5).unwrap();
+        assert_eq!(output, b"01234");
+        assert!(exceeded);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_limits() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(100)),
+            ..Limits::default()
+        };
+        let start = Instant::now();
+        let message = run_process("sleep", "sh", &["-c", "sleep 10"], vec![], &limits)
+            .unwrap()
+            .err()
+            .unwrap();
+        assert_eq!(
+            message,
+            "Plugin 'sleep' did not finish within 0.1 seconds, and was stopped."
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let limits = Limits {
+            max_output: 1000,
+            ..Limits::default()
+        };
+        let message = run_process("yes", "sh", &["-c", "yes"], vec![], &limits)
+            .unwrap()
+            .err()
+            .unwrap();
+        assert_eq!(
+            message,
+            "Plugin 'yes' exceeds the maximum output size of 1000 bytes, and was stopped."
+        );
+
+        let output = run_process("echo", "sh", &["-c", "cat"], b"abc".to_vec(), &limits)
+            .unwrap()
+            .unwrap();
+        assert_eq!(output.exit_code, Some(0));
+        assert_eq!(output.stdout, b"abc");
+    }
+
+    #[test]
+    fn project_settings() {
+        let mut config = config("");
+        config.paths.code = Some(PathBuf::from("other"));
+        let project = ProjectSettings::from_config(&config);
+
+        assert_eq!(project.parser, yarner_lib::ParserSettings::default());
+        assert_eq!(project.paths.code, Some(PathBuf::from("other")));
+        assert_eq!(project.paths.docs, Some(PathBuf::from("docs/")));
+
+        let mut context = Context {
+            config: Value::Table(Default::default()),
+            name: "a".to_string(),
+            yarner_version: YARNER_VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![],
+            parser: Some(project.parser.clone()),
+            paths: Some(project.paths.clone()),
+            language: project.language.clone(),
+        };
+        assert!(project.matches(&context));
+        context.parser.as_mut().unwrap().macro_end = ";".to_string();
+        assert!(!project.matches(&context));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn in_band_handshake() {
+        let project = Project::empty("handshake");
+        let log = project.path("calls.log");
+
+        // Opts into persistence when called once, and echoes the data of requests when persistent
+        let plugin_config = |version: u32| {
+            config(&format!(
+                r#"
+                [plugin.echo]
+                command = "sh"
+                timeout = 5
+                arguments = ["-c", '''
+if [ "$0" = "{persistent}" ]; then
+  echo persistent >> {log}
+  while read -r line; do
+    printf "%s\\n" "$line" | sed 's/^{{"jsonrpc":"2.0","id":\([0-9]*\),.*"params":{{"data":\(.*\),"removed":.*$/{{"jsonrpc":"2.0","id":\1,"result":\2}}/'
+  done
+else
+  echo once >> {log}
+  sed 's/^{{/{{"supports":{{"protocol_version":{version},"capabilities":["persistent"]}},/'
+fi''']
+                "#,
+                persistent = PERSISTENT_ARG,
+                log = log.display(),
+                version = version,
+            ))
+        };
+        let run = |config: &Config, host: &mut PluginHost| {
+            run_plugins(
+                config,
+                HashMap::new(),
+                &mut HashMap::new(),
+                PluginStage::Docs,
+                true,
+                host,
+            )
+        };
+
+        // The plugin opts into persistence in its first call, and is kept running from the second call on
+        let mut host = PluginHost::new(true);
+        let config = plugin_config(PROTOCOL_VERSION);
+        for _ in 0..3 {
+            run(&config, &mut host).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "once\npersistent\n");
+
+        // Plugins requiring a newer protocol are refused
+        let mut host = PluginHost::new(true);
+        let err = run(&plugin_config(PROTOCOL_VERSION + 1), &mut host).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Plugin 'echo' requires plugin protocol version"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_error_with_stderr() {
+        let config = config(
+            r#"
+            [plugin.broken]
+            command = "sh"
+            arguments = ["-c", "read request; echo first >&2; echo second >&2; exit 1"]
+            "#,
+        );
+        let plugins = plugin_order(&config).unwrap();
+        let plugin = &plugins[0];
+        let project = ProjectSettings::from_config(&config);
+        let context = Context {
+            config: plugin.config.clone(),
+            name: plugin.name.to_string(),
+            yarner_version: YARNER_VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![PERSISTENT_CAPABILITY.to_string()],
+            parser: Some(project.parser.clone()),
+            paths: Some(project.paths.clone()),
+            language: project.language.clone(),
+        };
+
+        let mut session = Session::start(plugin, context).unwrap();
+        let message = session
+            .process(&HashMap::new(), &plugin.settings.limits)
+            .unwrap_err();
+        assert_eq!(
+            message,
+            "Persistent plugin 'broken' exited unexpectedly.\n  Plugin stderr:\n    first\n    second"
+        );
+    }
+}
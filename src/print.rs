@@ -1,5 +1,5 @@
 pub mod docs {
-    use crate::code::RevCodeBlock;
+    use crate::code::{AddedBlock, RevCodeBlock};
     use crate::config::ParserSettings;
     use crate::util::JoinExt;
     use std::collections::HashMap;
@@ -44,6 +44,7 @@ pub mod docs {
         document: &Document,
         settings: &ParserSettings,
         code_blocks: &HashMap<(&Option<String>, &usize), &RevCodeBlock>,
+        added_blocks: &[&AddedBlock],
     ) -> String {
         let mut block_count: HashMap<&Option<String>, usize> = HashMap::new();
 
@@ -79,6 +80,19 @@ pub mod docs {
                         document.newline(),
                         &mut output,
                     );
+
+                    for added in added_blocks
+                        .iter()
+                        .filter(|added| added.after.0 == code_block.name && added.after.1 == index)
+                    {
+                        print_added_block_reverse(
+                            added,
+                            code_block,
+                            settings,
+                            document.newline(),
+                            &mut output,
+                        );
+                    }
                 }
             }
         }
@@ -191,6 +205,37 @@ pub mod docs {
         write!(write, "{}{}", fence_sequence, newline).unwrap();
     }
 
+    /// Prints a new block after the given block, with the same language
+    fn print_added_block_reverse(
+        added: &AddedBlock,
+        after: &CodeBlock,
+        settings: &ParserSettings,
+        newline: &str,
+        write: &mut impl Write,
+    ) {
+        let indent = &after.indent;
+        write!(write, "{}{}{}", newline, indent, settings.fence_sequence).unwrap();
+        if let Some(language) = &after.language {
+            write!(write, "{}", language).unwrap();
+        }
+        write!(write, "{}", newline).unwrap();
+        write!(
+            write,
+            "{}{} {}{}",
+            indent, settings.block_name_prefix, added.name, newline
+        )
+        .unwrap();
+
+        for line in &added.lines {
+            if line.is_empty() {
+                write!(write, "{}", newline).unwrap();
+            } else {
+                write!(write, "{}{}{}", indent, line, newline).unwrap();
+            }
+        }
+        write!(write, "{}{}", settings.fence_sequence, newline).unwrap();
+    }
+
     /// Prints a line of a code block
     fn print_line(
         line: &Line,
@@ -262,12 +307,30 @@ pub mod docs {
 }
 
 pub mod code {
+    use crate::code::source_map_labels;
     use crate::config::LanguageSettings;
     use crate::util::{Fallible, JoinExt, TryCollectExt};
     use std::collections::{HashMap, HashSet};
     use std::fmt::Write;
     use yarner_lib::{CodeBlock, Line};
 
+    /// Formats the compiled code with hidden block labels, as source map for code output without labels
+    pub fn print_source_map(
+        code_blocks: &HashMap<Option<&str>, Vec<&CodeBlock>>,
+        entry_blocks: &[&CodeBlock],
+        settings: &LanguageSettings,
+        newline: &str,
+    ) -> Fallible<String> {
+        let settings = LanguageSettings {
+            block_labels: Some(source_map_labels()),
+            clear_blank_lines: settings.clear_blank_lines,
+            eof_newline: settings.eof_newline,
+            source_map: true,
+            clean_code: false,
+        };
+        print_code(code_blocks, entry_blocks, Some(&settings), newline)
+    }
+
     /// Formats this `Document` as a string containing the compiled code
     pub fn print_code(
         code_blocks: &HashMap<Option<&str>, Vec<&CodeBlock>>,
@@ -293,7 +356,10 @@ pub mod code {
             .unwrap_or_default();
         let block_name_sep = '#';
 
-        let clean = settings.map_or(true, |set| set.clean_code || set.block_labels.is_none());
+        let clean = match settings {
+            Some(set) => set.clean_code || set.block_labels.is_none(),
+            None => true,
+        };
 
         let mut result = String::new();
         let mut block_count: HashMap<&Option<String>, usize> = HashMap::new();
@@ -507,6 +573,8 @@ pub mod code {
                 for _ in 0..newline.len() {
                     result.pop();
                 }
+                // Only macros on the current path are circular, not repeated invocations
+                trace.remove(name);
                 Ok(result)
             }
         }
@@ -514,6 +582,8 @@ pub mod code {
 
     /// Problems encountered while compiling the document
     #[derive(Debug)]
+    // Fields are only read through `Debug`, which formats the error messages
+    #[allow(dead_code)]
     pub enum CompileErrorKind {
         /// An unknown macro name was encountered
         UnknownMacro(String),
@@ -547,4 +617,65 @@ pub mod code {
     }
 
     impl std::error::Error for CompileError {}
+
+    #[cfg(test)]
+    mod tests {
+        use crate::config::Config;
+        use crate::parse;
+        use std::path::PathBuf;
+
+        fn compile(text: &str) -> Result<String, String> {
+            let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+            let path = PathBuf::from("README.md");
+            let (document, _) = parse::parse(text, &path, &path, false, &config.parser).unwrap();
+
+            let code_blocks = document.code_blocks_by_name();
+            super::print_code(&code_blocks, &code_blocks[&None], None, "\n")
+                .map_err(|err| err.to_string())
+        }
+
+        #[test]
+        fn repeated_macro() {
+            let text = r#"# Repeated
+
+```rust
+// ==> Inner.
+// ==> Outer.
+```
+
+```rust
+//- Outer
+// ==> Inner.
+```
+
+```rust
+//- Inner
+inner();
+```
+"#;
+            assert_eq!(compile(text).unwrap(), "inner();\ninner();\n");
+        }
+
+        #[test]
+        fn circular_macro() {
+            let text = r#"# Circular
+
+```rust
+// ==> Outer.
+```
+
+```rust
+//- Outer
+// ==> Inner.
+```
+
+```rust
+//- Inner
+// ==> Outer.
+```
+"#;
+            let err = compile(text).unwrap_err();
+            assert!(err.contains("Circular macro call: Outer"), "{}", err);
+        }
+    }
 }
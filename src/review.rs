@@ -0,0 +1,390 @@
+//! Review of changes to code blocks in reverse mode, interactively or by selection
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use similar::{ChangeTag, TextDiff};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use yarner_lib::Document;
+
+use crate::{
+    code::{BlockKey, RevCodeBlock},
+    config::ParserSettings,
+    merge,
+    util::Fallible,
+};
+
+/// Which changes to code blocks to play back in reverse mode
+#[derive(Debug)]
+pub enum Review {
+    /// Play back all changes
+    All,
+    /// Ask for each changed block
+    Interactive,
+    /// Play back changes of the selected blocks only
+    Select(Vec<BlockSelector>),
+}
+
+/// Selects code blocks by document, and optionally by block name and index, as `doc[#name[#index]]`
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockSelector {
+    file: PathBuf,
+    name: Option<Option<String>>,
+    index: Option<usize>,
+}
+
+impl FromStr for BlockSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '#');
+        let file = PathBuf::from(parts.next().unwrap_or(""));
+        let name = parts
+            .next()
+            .map(|name| Some(name.to_string()).filter(|name| !name.is_empty()));
+        let index = parts
+            .next()
+            .map(|index| {
+                index
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid block index '{}' in selection '{}'", index, s))
+            })
+            .transpose()?;
+
+        Ok(Self { file, name, index })
+    }
+}
+
+impl BlockSelector {
+    fn matches(&self, key: &BlockKey) -> bool {
+        self.file == key.0
+            && self.name.iter().all(|name| *name == key.1)
+            && self.index.iter().all(|index| *index == key.2)
+    }
+}
+
+/// Decision on a changed block
+enum Decision {
+    Accept,
+    Skip,
+    Edit,
+    Quit,
+}
+
+/// Reviews the changes of code blocks compared to the documents.
+/// Rejected blocks are removed from `code_blocks`, and returned.
+pub fn review_changes(
+    review: &Review,
+    documents: &HashMap<PathBuf, Document>,
+    settings: &ParserSettings,
+    code_blocks: &mut HashMap<BlockKey, RevCodeBlock>,
+) -> Fallible<HashMap<BlockKey, RevCodeBlock>> {
+    let mut skipped = HashMap::new();
+    if let Review::All = review {
+        return Ok(skipped);
+    }
+
+    let mut changed = vec![];
+    for (path, document) in documents {
+        let doc_blocks = merge::document_block_lines(document, settings);
+        for (key, block) in code_blocks.iter().filter(|(key, _)| &key.0 == path) {
+            if let Some(lines) = doc_blocks.get(&(key.1.clone(), key.2)) {
+                if *lines != block.lines {
+                    changed.push((key.clone(), lines.clone()));
+                }
+            }
+        }
+    }
+    changed.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut quit = false;
+    for (key, old_lines) in changed {
+        let accept = match review {
+            Review::All => true,
+            Review::Select(selectors) => selectors.iter().any(|sel| sel.matches(&key)),
+            Review::Interactive if quit => false,
+            Review::Interactive => loop {
+                let block = code_blocks.get_mut(&key).unwrap();
+                print_diff(&key, &old_lines, &block.lines)?;
+                match ask()? {
+                    Decision::Accept => break true,
+                    Decision::Skip => break false,
+                    Decision::Edit => block.lines = edit(&block.lines)?,
+                    Decision::Quit => {
+                        quit = true;
+                        break false;
+                    }
+                }
+            },
+        };
+
+        if !accept {
+            if let Some(block) = code_blocks.remove(&key) {
+                skipped.insert(key, block);
+            }
+        }
+    }
+
+    Ok(skipped)
+}
+
+/// Prints a colored diff of the changes to a block
+fn print_diff(key: &BlockKey, old: &[String], new: &[String]) -> Fallible {
+    let mut out = StandardStream::stdout(if io::stdout().is_terminal() {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    });
+
+    out.set_color(ColorSpec::new().set_bold(true))?;
+    writeln!(
+        out,
+        "{} # {} # {}",
+        key.0.display(),
+        key.1.as_deref().unwrap_or(""),
+        key.2
+    )?;
+    out.reset()?;
+
+    let old: Vec<_> = old.iter().map(|line| line.as_str()).collect();
+    let new: Vec<_> = new.iter().map(|line| line.as_str()).collect();
+    for change in TextDiff::from_slices(&old, &new).iter_all_changes() {
+        let (sign, color) = match change.tag() {
+            ChangeTag::Delete => ("-", Some(Color::Red)),
+            ChangeTag::Insert => ("+", Some(Color::Green)),
+            ChangeTag::Equal => (" ", None),
+        };
+        out.set_color(ColorSpec::new().set_fg(color))?;
+        writeln!(out, "{}{}", sign, change.value())?;
+    }
+    out.reset()?;
+    Ok(())
+}
+
+/// Asks the user for a decision on a changed block
+fn ask() -> Fallible<Decision> {
+    let stdin = io::stdin();
+    loop {
+        print!("Play back this change? [a]ccept, [s]kip, [e]dit, [q]uit: ");
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            return Ok(Decision::Quit);
+        }
+        match answer.trim() {
+            "a" => return Ok(Decision::Accept),
+            "s" => return Ok(Decision::Skip),
+            "e" => return Ok(Decision::Edit),
+            "q" => return Ok(Decision::Quit),
+            _ => {}
+        }
+    }
+}
+
+/// Lets the user edit the lines of a block in the editor given by `VISUAL` or `EDITOR`
+fn edit(lines: &[String]) -> Fallible<Vec<String>> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        });
+    edit_with(&editor, lines)
+}
+
+fn edit_with(editor: &str, lines: &[String]) -> Fallible<Vec<String>> {
+    let path = create_temp_file(&(lines.join("\n") + "\n"))?;
+
+    // The editor may be given with arguments, like `code --wait`
+    let mut parts = editor.split_whitespace();
+    let status = Command::new(parts.next().unwrap_or_default())
+        .args(parts)
+        .arg(&path)
+        .status()
+        .map_err(|err| format!("Unable to run editor '{}': {}", editor, err));
+    let content = fs::read_to_string(&path);
+    fs::remove_file(&path)?;
+
+    if !status?.success() {
+        return Err(format!("Editor '{}' exited with an error", editor).into());
+    }
+    Ok(content?.lines().map(|line| line.to_string()).collect())
+}
+
+/// Creates a new file with the given content in the temp directory.
+/// Existing files are never opened, so symlinks placed at the path are not followed.
+fn create_temp_file(content: &str) -> Fallible<PathBuf> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos())
+        .unwrap_or_default();
+
+    for attempt in 0..100 {
+        let path = env::temp_dir().join(format!(
+            "yarner-block-{}-{}-{}.txt",
+            std::process::id(),
+            nanos,
+            attempt
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                if let Err(err) = file.write_all(content.as_bytes()) {
+                    let _ = fs::remove_file(&path);
+                    return Err(err.into());
+                }
+                return Ok(path);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(format!(
+                    "Unable to create temporary file {}: {}",
+                    path.display(),
+                    err
+                )
+                .into())
+            }
+        }
+    }
+    Err("Unable to create a temporary file for editing".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, parse};
+
+    #[test]
+    fn parse_selectors() {
+        let key = (PathBuf::from("README.md"), Some("Functions".to_string()), 1);
+
+        let all: BlockSelector = "README.md".parse().unwrap();
+        assert!(all.matches(&key));
+
+        let by_name: BlockSelector = "README.md#Functions".parse().unwrap();
+        assert!(by_name.matches(&key));
+
+        let by_index: BlockSelector = "README.md#Functions#0".parse().unwrap();
+        assert!(!by_index.matches(&key));
+
+        let unnamed: BlockSelector = "README.md#".parse().unwrap();
+        assert!(!unnamed.matches(&key));
+        assert!(unnamed.matches(&(PathBuf::from("README.md"), None, 0)));
+
+        assert!("README.md#Functions#x".parse::<BlockSelector>().is_err());
+    }
+
+    fn documents(config: &Config) -> HashMap<PathBuf, Document> {
+        let text = r#"# Review
+
+```rust
+//- Functions
+fn a() {}
+```
+
+```rust
+//- Functions
+fn b() {}
+```
+"#;
+        let path = PathBuf::from("README.md");
+        let (doc, _) = parse::parse(text, &path, &path, false, &config.parser).unwrap();
+        HashMap::from([(path, doc)])
+    }
+
+    fn code_blocks() -> HashMap<BlockKey, RevCodeBlock> {
+        ["fn a() { changed(); }", "fn b() { changed(); }"]
+            .iter()
+            .enumerate()
+            .map(|(index, line)| {
+                let name = Some("Functions".to_string());
+                let block = RevCodeBlock {
+                    file: "README.md".to_string(),
+                    name: name.clone(),
+                    index,
+                    lines: vec![line.to_string()],
+                    indent: String::new(),
+                    code_file: PathBuf::from("code/README"),
+                    line: 2 + 4 * index,
+                };
+                ((PathBuf::from("README.md"), name, index), block)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn review_selected_blocks() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let documents = documents(&config);
+
+        let key_a = (PathBuf::from("README.md"), Some("Functions".to_string()), 0);
+        let key_b = (PathBuf::from("README.md"), Some("Functions".to_string()), 1);
+
+        let mut blocks = code_blocks();
+        let review = Review::Select(vec!["README.md#Functions#1".parse().unwrap()]);
+        let skipped = review_changes(&review, &documents, &config.parser, &mut blocks).unwrap();
+        assert_eq!(skipped.keys().collect::<Vec<_>>(), vec![&key_a]);
+        assert!(!blocks.contains_key(&key_a));
+        assert!(blocks.contains_key(&key_b));
+
+        let mut blocks = code_blocks();
+        let review = Review::Select(vec!["other.md".parse().unwrap()]);
+        let skipped = review_changes(&review, &documents, &config.parser, &mut blocks).unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert!(blocks.is_empty());
+
+        let mut blocks = code_blocks();
+        let skipped =
+            review_changes(&Review::All, &documents, &config.parser, &mut blocks).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn review_unchanged_blocks() {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let documents = documents(&config);
+
+        let mut blocks = code_blocks();
+        for block in blocks.values_mut() {
+            block.lines = vec![format!("fn {}() {{}}", ["a", "b"][block.index])];
+        }
+        let review = Review::Select(vec![]);
+        let skipped = review_changes(&review, &documents, &config.parser, &mut blocks).unwrap();
+        assert!(skipped.is_empty());
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edit_in_temp_file() {
+        let first = create_temp_file("first").unwrap();
+        let second = create_temp_file("second").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read_to_string(&first).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&second).unwrap(), "second");
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+
+        let lines = vec!["fn a() {}".to_string()];
+        let edited = edit_with("sed -i s/a/edited/", &lines).unwrap();
+        assert_eq!(edited, vec!["fn edited() {}"]);
+
+        let leftover = fs::read_dir(env::temp_dir()).unwrap().any(|entry| {
+            entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&format!("yarner-block-{}-", std::process::id()))
+        });
+        assert!(!leftover);
+    }
+}
@@ -0,0 +1,143 @@
+//! Temporary projects for tests, and running Yarner on them
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Config of a project with block labels for Rust, enabling the lock file and reverse mode
+pub const CONFIG: &str = concat!(
+    include_str!("create/Yarner.toml"),
+    r#"
+[language.rs]
+clear_blank_lines = true
+eof_newline = true
+
+    [language.rs.block_labels]
+    comment_start = "//"
+    block_start = "<@"
+    block_next = "<@>"
+    block_end = "@>"
+"#
+);
+
+/// Runs of Yarner change the working directory, so only one runs at a time
+static RUN: Mutex<()> = Mutex::new(());
+
+/// A temporary directory, removed when dropped
+pub struct Project {
+    pub dir: PathBuf,
+}
+
+impl Project {
+    /// Creates an empty directory
+    pub fn empty(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("yarner-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Project { dir }
+    }
+
+    /// Creates a project with the given `README.md`, and config [`CONFIG`]
+    pub fn new(name: &str, readme: &str) -> Self {
+        let project = Self::empty(name);
+        project.write("Yarner.toml", CONFIG);
+        project.write("README.md", readme);
+        project
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+
+    pub fn write(&self, path: &str, content: &str) {
+        let path = self.path(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, content).unwrap();
+    }
+
+    pub fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        Path::new(&self.path(path)).exists()
+    }
+
+    /// Runs a function in the project directory.
+    /// Returns its result, and the messages logged during the run.
+    pub fn run<T>(&self, func: impl FnOnce() -> T) -> (T, String) {
+        let _guard = RUN.lock().unwrap_or_else(|err| err.into_inner());
+        init_logger();
+
+        let original_dir = env::current_dir().unwrap();
+        env::set_current_dir(&self.dir).unwrap();
+        CAPTURED.with(|captured| *captured.borrow_mut() = Some(String::new()));
+
+        let result = func();
+
+        let log = CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default());
+        env::set_current_dir(original_dir).unwrap();
+        (result, log)
+    }
+
+    /// Runs Yarner with the given arguments in the project directory.
+    /// Returns the result, and the messages logged during the run.
+    pub fn yarner(&self, args: &[&str]) -> (Result<(), String>, String) {
+        self.run(|| {
+            crate::app()
+                .get_matches_from_safe(std::iter::once("yarner").chain(args.iter().copied()))
+                .map_err(|err| err.to_string())
+                .and_then(|matches| crate::run(matches).map_err(|err| err.to_string()))
+        })
+    }
+
+    /// Runs Yarner in the project directory, and panics if it fails. Returns the logged messages.
+    pub fn yarner_ok(&self, args: &[&str]) -> String {
+        let (result, log) = self.yarner(args);
+        if let Err(err) = result {
+            panic!("yarner {:?} failed: {}\n{}", args, err, log);
+        }
+        log
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+thread_local! {
+    /// Messages logged by the current thread, while running Yarner
+    static CAPTURED: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Logger capturing the messages of runs of Yarner
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED.with(|captured| {
+            if let Some(captured) = captured.borrow_mut().as_mut() {
+                captured.push_str(&format!("[{}] {}\n", record.level(), record.args()));
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn init_logger() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| log::set_logger(&CaptureLogger).unwrap());
+    log::set_max_level(LevelFilter::Info);
+}
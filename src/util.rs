@@ -7,6 +7,7 @@ pub trait TryCollectExt<T, E>
 where
     Self: Iterator<Item = Result<T, E>> + Sized,
 {
+    #[allow(clippy::manual_try_fold)]
     fn try_collect(self) -> Result<Vec<T>, Vec<E>> {
         let vals = Vec::with_capacity(self.size_hint().0);
 
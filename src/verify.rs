@@ -0,0 +1,379 @@
+//! Strict validation of block labels in code output, as required by reverse mode
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+
+use yarner_lib::{CodeBlock, Document, Line};
+
+use crate::{
+    code::{self, BlockKey, LabelKind},
+    config::{BlockLabels, Config},
+    files,
+    util::Fallible,
+};
+
+/// A problem with the block labels of a code file
+#[derive(Debug)]
+pub struct LabelError {
+    /// Code file of the problem
+    pub code_file: PathBuf,
+    /// Line number in the code file
+    pub line: usize,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl Display for LabelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.code_file.display(),
+            self.line,
+            self.message
+        )
+    }
+}
+
+/// Verifies the block labels of all code files with block labels against the documents
+pub fn verify_labels(
+    code_files: &HashSet<PathBuf>,
+    config: &Config,
+    documents: &HashMap<PathBuf, Document>,
+) -> Fallible<Vec<LabelError>> {
+    let blocks = DocumentBlocks::new(documents);
+
+    let mut files: Vec<_> = code_files.iter().collect();
+    files.sort();
+
+    let mut errors = vec![];
+    for file in files {
+        let labels = file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| config.language.get(ext))
+            .and_then(|lang| lang.block_labels.as_ref());
+
+        if let Some(labels) = labels {
+            let source = files::read_file_string(file)?;
+            errors.extend(verify_source(&source, file, labels, &blocks));
+        }
+    }
+    Ok(errors)
+}
+
+/// Code blocks of all documents, by block key
+struct DocumentBlocks<'a> {
+    blocks: HashMap<BlockKey, &'a CodeBlock>,
+}
+
+impl<'a> DocumentBlocks<'a> {
+    fn new(documents: &'a HashMap<PathBuf, Document>) -> Self {
+        let mut blocks = HashMap::new();
+        for (path, document) in documents {
+            let mut block_count: HashMap<&Option<String>, usize> = HashMap::new();
+            for block in document.code_blocks() {
+                let count = block_count.entry(&block.name).or_default();
+                blocks.insert((path.clone(), block.name.clone(), *count), block);
+                *count += 1;
+            }
+        }
+        Self { blocks }
+    }
+
+    /// Number of macro invocations of `name` in the given block, or `None` if the block does not exist
+    fn invocations(&self, key: &BlockKey, name: &str) -> Option<usize> {
+        self.blocks.get(key).map(|block| {
+            block
+                .source
+                .iter()
+                .filter(|line| matches!(line, Line::Macro { name: n, .. } if n == name))
+                .count()
+        })
+    }
+}
+
+/// A block opened by a label, but not closed yet
+struct OpenBlock {
+    key: BlockKey,
+    line: usize,
+    /// Number of nested blocks started so far, by name
+    children: HashMap<String, usize>,
+}
+
+fn verify_source(
+    source: &str,
+    code_file: &Path,
+    block_labels: &BlockLabels,
+    blocks: &DocumentBlocks,
+) -> Vec<LabelError> {
+    let mut errors = vec![];
+    let mut error = |line: usize, message: String| {
+        errors.push(LabelError {
+            code_file: code_file.to_owned(),
+            line,
+            message,
+        })
+    };
+
+    let mut stack: Vec<OpenBlock> = vec![];
+    let mut top_level: HashMap<BlockKey, usize> = HashMap::new();
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let label = match code::parse_label(line.trim_start(), block_labels) {
+            None => continue,
+            Some(Err(err)) => {
+                error(line_number, format!("Invalid block label: {}", err));
+                continue;
+            }
+            Some(Ok(label)) => label,
+        };
+        let key = label.key();
+
+        match label.kind {
+            LabelKind::Start => {
+                if let Some(parent) = stack.last_mut() {
+                    match &label.name {
+                        None => error(
+                            line_number,
+                            format!(
+                                "Unnamed block {} nested in block {}",
+                                format_key(&key),
+                                format_key(&parent.key)
+                            ),
+                        ),
+                        Some(name) => {
+                            if label.index != 0 {
+                                error(
+                                    line_number,
+                                    format!(
+                                        "Nested block {} must start with index 0",
+                                        format_key(&key)
+                                    ),
+                                );
+                            }
+
+                            let count = parent.children.entry(name.clone()).or_default();
+                            *count += 1;
+                            match blocks.invocations(&parent.key, name) {
+                                Some(0) => error(
+                                    line_number,
+                                    format!(
+                                        "Block {} is not referenced by a macro in enclosing block {}",
+                                        format_key(&key),
+                                        format_key(&parent.key)
+                                    ),
+                                ),
+                                Some(invocations) if *count > invocations => error(
+                                    line_number,
+                                    format!(
+                                        "Duplicate block {} in enclosing block {}, which references it {} time(s)",
+                                        format_key(&key),
+                                        format_key(&parent.key),
+                                        invocations
+                                    ),
+                                ),
+                                _ => {}
+                            }
+                        }
+                    }
+                } else if let Some(first) = top_level.insert(key.clone(), line_number) {
+                    error(
+                        line_number,
+                        format!(
+                            "Duplicate block {}, already labelled in line {}",
+                            format_key(&key),
+                            first
+                        ),
+                    );
+                }
+            }
+            LabelKind::Next => match stack.pop() {
+                None => error(
+                    line_number,
+                    format!(
+                        "Continuation label of block {} outside of any block",
+                        format_key(&key)
+                    ),
+                ),
+                Some(previous) => {
+                    if previous.key.1 != label.name || previous.key.2 + 1 != label.index {
+                        error(
+                            line_number,
+                            format!(
+                                "Continuation label of block {} does not follow block {}, started in line {}",
+                                format_key(&key),
+                                format_key(&previous.key),
+                                previous.line
+                            ),
+                        );
+                    }
+                }
+            },
+            LabelKind::End => {
+                match stack.iter().rposition(|open| open.key == key) {
+                    Some(pos) => {
+                        for open in stack.drain(pos + 1..) {
+                            error(
+                                open.line,
+                                format!(
+                                    "Block {} is not closed before the end of enclosing block {}",
+                                    format_key(&open.key),
+                                    format_key(&key)
+                                ),
+                            );
+                        }
+                        stack.pop();
+                    }
+                    None => error(
+                        line_number,
+                        match stack.last() {
+                            Some(open) => format!(
+                                "End label of block {} does not match open block {}, started in line {}",
+                                format_key(&key),
+                                format_key(&open.key),
+                                open.line
+                            ),
+                            None => format!(
+                                "End label of block {} without matching start label",
+                                format_key(&key)
+                            ),
+                        },
+                    ),
+                }
+                continue;
+            }
+        }
+
+        if !blocks.blocks.contains_key(&key) {
+            error(
+                line_number,
+                format!("Block {} not found in the documents", format_key(&key)),
+            );
+        }
+        stack.push(OpenBlock {
+            key,
+            line: line_number,
+            children: HashMap::new(),
+        });
+    }
+
+    for open in stack {
+        error(
+            open.line,
+            format!("Block {} is not closed", format_key(&open.key)),
+        );
+    }
+
+    errors
+}
+
+/// Formats a block key like in block labels, as `file#name#index`
+fn format_key(key: &BlockKey) -> String {
+    format!(
+        "'{}#{}#{}'",
+        key.0.display(),
+        key.1.as_deref().unwrap_or(""),
+        key.2
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, parse};
+
+    const DOCUMENT: &str = r#"# Test
+
+```rust
+fn main() {
+    // ==> Print.
+}
+```
+
+```rust
+//- Print
+println!("Hello");
+```
+
+```rust
+//- Print
+println!("World");
+```
+"#;
+
+    fn verify(code: &str) -> Vec<(usize, String)> {
+        let config = toml::from_str::<Config>(include_str!("create/Yarner.toml")).unwrap();
+        let labels = BlockLabels {
+            comment_start: "//".to_string(),
+            comment_end: None,
+            block_start: "<@".to_string(),
+            block_next: "<@>".to_string(),
+            block_end: "@>".to_string(),
+        };
+
+        let path = Path::new("README.md");
+        let (document, _) = parse::parse(DOCUMENT, path, path, false, &config.parser).unwrap();
+        let mut documents = HashMap::new();
+        documents.insert(PathBuf::from("README.md"), document);
+
+        verify_source(
+            code,
+            Path::new("main.rs"),
+            &labels,
+            &DocumentBlocks::new(&documents),
+        )
+        .into_iter()
+        .map(|err| (err.line, err.message))
+        .collect()
+    }
+
+    #[test]
+    fn valid_labels() {
+        let code = r#"// <@README.md##0
+fn main() {
+    // <@README.md#Print#0
+    println!("Hello");
+    // <@>README.md#Print#1
+    println!("World");
+    // @>README.md#Print#1
+}
+// @>README.md##0
+"#;
+        assert_eq!(verify(code), vec![]);
+    }
+
+    #[test]
+    fn invalid_labels() {
+        let code = r#"// <@README.md##0
+fn main() {
+    // <@README.md#Print#0
+    println!("Hello");
+    // <@>README.md#Print#2
+    println!("World");
+    // @>README.md#Print#2
+    // <@README.md#Print#0
+    // @>README.md#Print#1
+}
+// @>README.md##0
+// <@README.md#Missing#0
+"#;
+        let errors = verify(code);
+        let lines: Vec<_> = errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![5, 5, 8, 9, 8, 12, 12], "{:?}", errors);
+        assert!(errors[0].1.starts_with("Continuation label"));
+        assert!(errors[1].1.ends_with("not found in the documents"));
+        assert!(errors[2]
+            .1
+            .starts_with("Duplicate block 'README.md#Print#0'"));
+        assert!(errors[3]
+            .1
+            .starts_with("End label of block 'README.md#Print#1'"));
+        assert!(errors[4]
+            .1
+            .ends_with("not closed before the end of enclosing block 'README.md##0'"));
+        assert!(errors[5].1.ends_with("not found in the documents"));
+        assert!(errors[6].1.ends_with("is not closed"));
+    }
+}
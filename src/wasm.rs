@@ -0,0 +1,623 @@
+//! In-process WebAssembly plugins, run in a sandbox with a minimal WASI implementation.
+//!
+//! Plugins have access to stdin, stdout and stderr, command line arguments, clocks and random numbers.
+//! There is no access to the file system, the network or environment variables.
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use wasmi::{
+    core::TrapCode, Caller, Config, Engine, Error, Extern, ExternType, Linker, Memory, Module,
+    Store, StoreLimits, StoreLimitsBuilder, Val,
+};
+
+use crate::{files, util::Fallible};
+
+/// Module name of WASI preview 1 imports
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// Maximum size of a plugin's linear memory
+const MAX_MEMORY: usize = 1024 * 1024 * 1024;
+
+/// WASI error numbers
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_BADF: i32 = 8;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_INVAL: i32 = 28;
+const ERRNO_NOSYS: i32 = 52;
+const ERRNO_SPIPE: i32 = 70;
+
+/// WASI functions provided to plugins
+const SUPPORTED: &[&str] = &[
+    "args_sizes_get",
+    "args_get",
+    "environ_sizes_get",
+    "environ_get",
+    "fd_read",
+    "fd_write",
+    "fd_fdstat_get",
+    "fd_prestat_get",
+    "fd_prestat_dir_name",
+    "fd_close",
+    "fd_seek",
+    "clock_time_get",
+    "random_get",
+    "sched_yield",
+    "proc_exit",
+];
+
+/// WASI file type of stdin, stdout and stderr
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+
+/// Output of a WebAssembly plugin
+pub struct WasmOutput {
+    /// Exit code, or `None` if the plugin trapped
+    pub exit_code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// State of a running plugin, accessed by the WASI functions
+struct WasiState {
+    args: Vec<Vec<u8>>,
+    stdin: Vec<u8>,
+    stdin_pos: usize,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    max_output: usize,
+    output_exceeded: bool,
+    start: Instant,
+    random: u64,
+    limits: StoreLimits,
+}
+
+/// Runs a WebAssembly plugin module with the given arguments and input to stdin.
+/// The computation is limited to `max_fuel`, if given, which is consumed roughly by one unit per executed instruction.
+/// Returns an error message if the plugin was stopped due to exceeding its limits.
+pub fn run_wasm(
+    name: &str,
+    path: &Path,
+    arguments: &[&str],
+    input: Vec<u8>,
+    max_fuel: Option<u64>,
+    max_output: usize,
+) -> Fallible<Result<WasmOutput, String>> {
+    let wasm = files::read_file(path).map_err(|err| {
+        format!(
+            "Failed to load WebAssembly plugin '{}' from {}: {}",
+            name,
+            path.display(),
+            err
+        )
+    })?;
+
+    let mut config = Config::default();
+    config.consume_fuel(max_fuel.is_some());
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, &wasm[..]).map_err(|err| {
+        format!(
+            "Invalid WebAssembly plugin '{}' in {}: {}",
+            name,
+            path.display(),
+            err
+        )
+    })?;
+
+    let program = path
+        .file_name()
+        .map(|file| file.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let state = WasiState {
+        args: std::iter::once(program.as_str())
+            .chain(arguments.iter().copied())
+            .map(|arg| arg.as_bytes().to_vec())
+            .collect(),
+        stdin: input,
+        stdin_pos: 0,
+        stdout: vec![],
+        stderr: vec![],
+        max_output,
+        output_exceeded: false,
+        start: Instant::now(),
+        random: random_seed(),
+        limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+    };
+    let mut store = Store::new(&engine, state);
+    store.limiter(|state| &mut state.limits);
+    if let Some(max_fuel) = max_fuel {
+        store.set_fuel(max_fuel).map_err(|err| err.to_string())?;
+    }
+
+    let linker = wasi_linker(&engine, &module)?;
+    let result = linker
+        .instantiate(&mut store, &module)
+        .and_then(|pre| pre.start(&mut store))
+        .and_then(|instance| {
+            instance
+                .get_typed_func::<(), ()>(&store, "_start")
+                .and_then(|start| start.call(&mut store, ()))
+        });
+
+    let exit_code = match result {
+        Ok(()) => Some(0),
+        Err(err) => {
+            if store.data().output_exceeded {
+                return Ok(Err(format!(
+                    "Plugin '{}' exceeds the maximum output size of {} bytes, and was stopped.",
+                    name, max_output
+                )));
+            }
+            if let (Some(TrapCode::OutOfFuel), Some(max_fuel)) = (err.as_trap_code(), max_fuel) {
+                return Ok(Err(format!(
+                    "Plugin '{}' exceeds its fuel limit of {}, and was stopped.",
+                    name, max_fuel
+                )));
+            }
+            match err.i32_exit_status() {
+                Some(code) => Some(code),
+                None => {
+                    let state = store.data_mut();
+                    state
+                        .stderr
+                        .extend(format!("WebAssembly error: {}\n", err).as_bytes());
+                    None
+                }
+            }
+        }
+    };
+
+    let state = store.into_data();
+    Ok(Ok(WasmOutput {
+        exit_code,
+        stdout: state.stdout,
+        stderr: state.stderr,
+    }))
+}
+
+/// Seed for random numbers. Not suitable for cryptography
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default()
+        | 1
+}
+
+/// Creates a linker with the supported WASI functions.
+/// Other WASI functions imported by the module return error `ENOSYS`.
+fn wasi_linker(engine: &Engine, module: &Module) -> Fallible<Linker<WasiState>> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        WASI_MODULE,
+        "args_sizes_get",
+        |mut caller: Caller<'_, WasiState>, count: u32, size: u32| -> Result<i32, Error> {
+            let args = &caller.data().args;
+            let (num, len) = (
+                args.len() as u32,
+                args.iter().map(|arg| arg.len() as u32 + 1).sum::<u32>(),
+            );
+            Ok(errno(
+                write_u32(&mut caller, count, num).and_then(|_| write_u32(&mut caller, size, len)),
+            ))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "args_get",
+        |mut caller: Caller<'_, WasiState>, argv: u32, buf: u32| -> Result<i32, Error> {
+            let args = caller.data().args.clone();
+            let mut offset = buf;
+            for (idx, arg) in args.iter().enumerate() {
+                let mut bytes = arg.clone();
+                bytes.push(0);
+                let result = element(argv, idx as u32, 4)
+                    .and_then(|ptr| write_u32(&mut caller, ptr, offset))
+                    .and_then(|_| write_bytes(&mut caller, offset, &bytes))
+                    .and_then(|_| offset.checked_add(bytes.len() as u32).ok_or(ERRNO_FAULT));
+                match result {
+                    Ok(next) => offset = next,
+                    Err(errno) => return Ok(errno),
+                }
+            }
+            Ok(ERRNO_SUCCESS)
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "environ_sizes_get",
+        |mut caller: Caller<'_, WasiState>, count: u32, size: u32| -> Result<i32, Error> {
+            Ok(errno(
+                write_u32(&mut caller, count, 0).and_then(|_| write_u32(&mut caller, size, 0)),
+            ))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "environ_get",
+        |_: Caller<'_, WasiState>, _: i32, _: i32| -> Result<i32, Error> { Ok(ERRNO_SUCCESS) },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_read",
+        |mut caller: Caller<'_, WasiState>,
+         fd: i32,
+         iovs: u32,
+         iovs_len: u32,
+         nread: u32|
+         -> Result<i32, Error> {
+            if fd != 0 {
+                return Ok(ERRNO_BADF);
+            }
+            let mut total = 0u32;
+            for idx in 0..iovs_len {
+                let (ptr, len) =
+                    match element(iovs, idx, 8).and_then(|iov| read_iovec(&caller, iov)) {
+                        Ok(iov) => iov,
+                        Err(errno) => return Ok(errno),
+                    };
+                let state = caller.data();
+                let count = (len as usize).min(state.stdin.len() - state.stdin_pos);
+                let bytes = state.stdin[state.stdin_pos..state.stdin_pos + count].to_vec();
+                if let Err(errno) = write_bytes(&mut caller, ptr, &bytes) {
+                    return Ok(errno);
+                }
+                caller.data_mut().stdin_pos += count;
+                total = match total.checked_add(count as u32) {
+                    Some(total) => total,
+                    None => return Ok(ERRNO_INVAL),
+                };
+                if count < len as usize {
+                    break;
+                }
+            }
+            Ok(errno(write_u32(&mut caller, nread, total)))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_write",
+        |mut caller: Caller<'_, WasiState>,
+         fd: i32,
+         iovs: u32,
+         iovs_len: u32,
+         nwritten: u32|
+         -> Result<i32, Error> {
+            if fd != 1 && fd != 2 {
+                return Ok(ERRNO_BADF);
+            }
+            let mut total = 0u32;
+            for idx in 0..iovs_len {
+                let bytes = match element(iovs, idx, 8)
+                    .and_then(|iov| read_iovec(&caller, iov))
+                    .and_then(|(ptr, len)| read_bytes(&caller, ptr, len))
+                {
+                    Ok(bytes) => bytes,
+                    Err(errno) => return Ok(errno),
+                };
+                let state = caller.data_mut();
+                let out = if fd == 1 {
+                    &mut state.stdout
+                } else {
+                    &mut state.stderr
+                };
+                if out.len() + bytes.len() > state.max_output {
+                    state.output_exceeded = true;
+                    return Err(Error::new("maximum output size exceeded"));
+                }
+                out.extend(&bytes);
+                total = match total.checked_add(bytes.len() as u32) {
+                    Some(total) => total,
+                    None => return Ok(ERRNO_INVAL),
+                };
+            }
+            Ok(errno(write_u32(&mut caller, nwritten, total)))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_fdstat_get",
+        |mut caller: Caller<'_, WasiState>, fd: i32, buf: u32| -> Result<i32, Error> {
+            if !(0..=2).contains(&fd) {
+                return Ok(ERRNO_BADF);
+            }
+            let mut stat = [0u8; 24];
+            stat[0] = FILETYPE_CHARACTER_DEVICE;
+            Ok(errno(write_bytes(&mut caller, buf, &stat)))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_prestat_get",
+        // No pre-opened directories, i.e. no file system access
+        |_: Caller<'_, WasiState>, _: i32, _: i32| -> Result<i32, Error> { Ok(ERRNO_BADF) },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_prestat_dir_name",
+        |_: Caller<'_, WasiState>, _: i32, _: i32, _: i32| -> Result<i32, Error> { Ok(ERRNO_BADF) },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_close",
+        |_: Caller<'_, WasiState>, fd: i32| -> Result<i32, Error> {
+            Ok(if (0..=2).contains(&fd) {
+                ERRNO_SUCCESS
+            } else {
+                ERRNO_BADF
+            })
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "fd_seek",
+        |_: Caller<'_, WasiState>, _: i32, _: i64, _: i32, _: i32| -> Result<i32, Error> {
+            Ok(ERRNO_SPIPE)
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "clock_time_get",
+        |mut caller: Caller<'_, WasiState>, id: i32, _: i64, time: u32| -> Result<i32, Error> {
+            let nanos = match id {
+                // Realtime
+                0 => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_nanos() as u64)
+                    .unwrap_or_default(),
+                // Monotonic, process and thread CPU time
+                1..=3 => caller.data().start.elapsed().as_nanos() as u64,
+                _ => return Ok(ERRNO_INVAL),
+            };
+            Ok(errno(write_bytes(&mut caller, time, &nanos.to_le_bytes())))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "random_get",
+        |mut caller: Caller<'_, WasiState>, buf: u32, len: u32| -> Result<i32, Error> {
+            if let Err(errno) = check_bounds(&caller, buf, len) {
+                return Ok(errno);
+            }
+            let mut bytes = Vec::with_capacity(len as usize);
+            let state = caller.data_mut();
+            while bytes.len() < len as usize {
+                // xorshift64
+                state.random ^= state.random << 13;
+                state.random ^= state.random >> 7;
+                state.random ^= state.random << 17;
+                bytes.extend(state.random.to_le_bytes());
+            }
+            bytes.truncate(len as usize);
+            Ok(errno(write_bytes(&mut caller, buf, &bytes)))
+        },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "sched_yield",
+        |_: Caller<'_, WasiState>| -> Result<i32, Error> { Ok(ERRNO_SUCCESS) },
+    )?;
+    linker.func_wrap(
+        WASI_MODULE,
+        "proc_exit",
+        |_: Caller<'_, WasiState>, code: i32| -> Result<(), Error> { Err(Error::i32_exit(code)) },
+    )?;
+
+    for import in module.imports() {
+        if import.module() != WASI_MODULE || SUPPORTED.contains(&import.name()) {
+            continue;
+        }
+        if let ExternType::Func(func_type) = import.ty() {
+            let name = import.name().to_string();
+            let returns_errno = func_type.results().len() == 1;
+            linker.func_new(
+                WASI_MODULE,
+                import.name(),
+                func_type.clone(),
+                move |_, _, results| {
+                    if returns_errno {
+                        results[0] = Val::I32(ERRNO_NOSYS);
+                        Ok(())
+                    } else {
+                        Err(Error::new(format!(
+                            "WASI function {} is not supported",
+                            name
+                        )))
+                    }
+                },
+            )?;
+        }
+    }
+
+    Ok(linker)
+}
+
+/// Converts the result of memory access to an error number
+fn errno(result: Result<(), i32>) -> i32 {
+    result.err().unwrap_or(ERRNO_SUCCESS)
+}
+
+fn memory(caller: &Caller<'_, WasiState>) -> Result<Memory, i32> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Ok(memory),
+        _ => Err(ERRNO_FAULT),
+    }
+}
+
+/// Address of element `idx` of an array at `ptr`, with elements of `size` bytes
+fn element(ptr: u32, idx: u32, size: u32) -> Result<u32, i32> {
+    idx.checked_mul(size)
+        .and_then(|offset| ptr.checked_add(offset))
+        .ok_or(ERRNO_FAULT)
+}
+
+/// Checks that `len` bytes at `ptr` are within the plugin's memory
+fn check_bounds(caller: &Caller<'_, WasiState>, ptr: u32, len: u32) -> Result<(), i32> {
+    let size = memory(caller)?.data(caller).len();
+    match (ptr as usize).checked_add(len as usize) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(ERRNO_FAULT),
+    }
+}
+
+fn read_bytes(caller: &Caller<'_, WasiState>, ptr: u32, len: u32) -> Result<Vec<u8>, i32> {
+    check_bounds(caller, ptr, len)?;
+    let mut bytes = vec![0; len as usize];
+    memory(caller)?
+        .read(caller, ptr as usize, &mut bytes)
+        .map_err(|_| ERRNO_FAULT)?;
+    Ok(bytes)
+}
+
+fn write_bytes(caller: &mut Caller<'_, WasiState>, ptr: u32, bytes: &[u8]) -> Result<(), i32> {
+    memory(caller)?
+        .write(caller, ptr as usize, bytes)
+        .map_err(|_| ERRNO_FAULT)
+}
+
+fn write_u32(caller: &mut Caller<'_, WasiState>, ptr: u32, value: u32) -> Result<(), i32> {
+    write_bytes(caller, ptr, &value.to_le_bytes())
+}
+
+/// Reads a WASI `iovec`, as pointer and length
+fn read_iovec(caller: &Caller<'_, WasiState>, ptr: u32) -> Result<(u32, u32), i32> {
+    let bytes = read_bytes(caller, ptr, 8)?;
+    Ok((
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Project;
+    use std::fs;
+
+    const ECHO: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")
+            (loop $read
+              (i32.store (i32.const 0) (i32.const 16))
+              (i32.store (i32.const 4) (i32.const 4096))
+              (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+              (if (i32.gt_u (i32.load (i32.const 8)) (i32.const 0))
+                (then
+                  (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                  (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 12)))
+                  (br $read))))))
+    "#;
+
+    const EXIT: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+          (import "wasi_snapshot_preview1" "path_open"
+            (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (func (export "_start")
+            (call $proc_exit
+              (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 0)
+                (i32.const 0) (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 0)))))
+    "#;
+
+    const LOOP: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "_start")
+            (loop $forever (br $forever))))
+    "#;
+
+    fn run(name: &str, wat: &str, input: &[u8], max_output: usize) -> Result<WasmOutput, String> {
+        run_with_fuel(name, wat, input, max_output, Some(1_000_000))
+    }
+
+    fn run_with_fuel(
+        name: &str,
+        wat: &str,
+        input: &[u8],
+        max_output: usize,
+        max_fuel: Option<u64>,
+    ) -> Result<WasmOutput, String> {
+        let project = Project::empty(&format!("wasm-{}", name));
+        let path = project.path(&format!("{}.wasm", name));
+        fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+
+        run_wasm(name, &path, &[], input.to_vec(), max_fuel, max_output).unwrap()
+    }
+
+    #[test]
+    fn echo_input() {
+        let input = "x".repeat(10_000);
+        let output = run("echo", ECHO, input.as_bytes(), 100_000).unwrap();
+        assert_eq!(output.exit_code, Some(0));
+        assert_eq!(output.stdout, input.as_bytes());
+
+        let message = run("echo", ECHO, input.as_bytes(), 5_000).err().unwrap();
+        assert!(message.contains("exceeds the maximum output size of 5000 bytes"));
+    }
+
+    #[test]
+    fn exit_code_and_unsupported_functions() {
+        let output = run("exit", EXIT, &[], 100).unwrap();
+        assert_eq!(output.exit_code, Some(ERRNO_NOSYS));
+    }
+
+    #[test]
+    fn max_fuel() {
+        let message = run("loop", LOOP, &[], 100).err().unwrap();
+        assert!(message.contains("exceeds its fuel limit of 1000000"));
+    }
+
+    /// Exit code of a module calling WASI functions with the given invalid arguments.
+    /// Memory starts with an `iovec` of maximum length.
+    fn invalid_call(name: &str, call: &str, input: &[u8]) -> Option<i32> {
+        let wat = format!(
+            r#"
+            (module
+              (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+              (memory (export "memory") 1)
+              (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const -1))
+                (call $proc_exit {})))
+            "#,
+            call
+        );
+        run(name, &wat, input, 100).unwrap().exit_code
+    }
+
+    #[test]
+    fn invalid_memory_access() {
+        let read = "(call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8))";
+        assert_eq!(invalid_call("read-max", read, b"abc"), Some(ERRNO_SUCCESS));
+        let read = "(call $fd_read (i32.const 0) (i32.const -4) (i32.const 1) (i32.const 8))";
+        assert_eq!(invalid_call("read-iovs", read, b"abc"), Some(ERRNO_FAULT));
+        let read = "(call $fd_read (i32.const 0) (i32.const 65528) (i32.const -1) (i32.const 8))";
+        assert_eq!(invalid_call("read-count", read, b""), Some(ERRNO_FAULT));
+
+        let write = "(call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))";
+        assert_eq!(invalid_call("write-max", write, b""), Some(ERRNO_FAULT));
+        let write = "(call $fd_write (i32.const 1) (i32.const -8) (i32.const 2) (i32.const 8))";
+        assert_eq!(invalid_call("write-iovs", write, b""), Some(ERRNO_FAULT));
+
+        let args = "(call $args_get (i32.const -4) (i32.const 16))";
+        assert_eq!(invalid_call("args-argv", args, b""), Some(ERRNO_FAULT));
+        let args = "(call $args_get (i32.const 0) (i32.const -1))";
+        assert_eq!(invalid_call("args-buf", args, b""), Some(ERRNO_FAULT));
+
+        let random = "(call $random_get (i32.const 16) (i32.const -1))";
+        assert_eq!(invalid_call("random-max", random, b""), Some(ERRNO_FAULT));
+    }
+
+    #[test]
+    fn unlimited_fuel() {
+        let output = run_with_fuel("exit-unlimited", EXIT, &[], 100, None).unwrap();
+        assert_eq!(output.exit_code, Some(ERRNO_NOSYS));
+    }
+}
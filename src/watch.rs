@@ -1,4 +1,9 @@
-use crate::{cmd, util::Fallible};
+use crate::{
+    cmd,
+    output::{Output, OutputMode},
+    plugin::PluginHost,
+    util::Fallible,
+};
 
 use crate::cmd::BuildMode;
 use clap::ArgMatches;
@@ -25,6 +30,7 @@ pub fn watch(
     args: ArgMatches,
     watch_sources: impl Iterator<Item = PathBuf>,
     watch_code: impl Iterator<Item = PathBuf>,
+    mut plugins: PluginHost,
 ) -> Fallible {
     info!("Watching for changes...");
 
@@ -58,12 +64,19 @@ pub fn watch(
 
         let curr_dir = env::current_dir()?;
 
+        let mut output = Output::new(OutputMode::Write);
         let (config, mut watch_sources_new, watch_code_new) =
-            cmd::run_with_args(&args, Some(build_type), false)?;
+            cmd::run_with_args(&args, Some(build_type), false, &mut plugins, &mut output)?;
 
         if build_type == BuildMode::Reverse {
             env::set_current_dir(&curr_dir)?;
-            cmd::run_with_args(&args, Some(BuildMode::ForwardDocs), false)?;
+            cmd::run_with_args(
+                &args,
+                Some(BuildMode::ForwardDocs),
+                false,
+                &mut plugins,
+                &mut output,
+            )?;
         }
 
         env::set_current_dir(&curr_dir)?;
@@ -91,7 +104,7 @@ fn update_watcher(
         watcher.unwatch(path)?;
     }
     for path in new_files.difference(old_files) {
-        watcher.watch(&path, RecursiveMode::NonRecursive)?;
+        watcher.watch(path, RecursiveMode::NonRecursive)?;
     }
     Ok(())
 }
@@ -1,11 +1,12 @@
 //! The internal representation of a literate document
+use crate::ParserSettings;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::default::Default;
 use std::path::{Path, PathBuf};
 
 /// A representation of a `Document` of literate code
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     /// The nodes forming the document
     pub nodes: Vec<Node>,
@@ -14,7 +15,7 @@ pub struct Document {
 }
 
 /// A node, representing text and code blocks, as well as transclusions
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Node {
     /// A text block
     Text(TextBlock),
@@ -83,10 +84,198 @@ impl Document {
         }
         entries
     }
+
+    /// Gets all the code blocks of this document, for modification
+    pub fn code_blocks_mut(&mut self) -> impl Iterator<Item = &mut CodeBlock> {
+        self.nodes.iter_mut().filter_map(|node| match node {
+            Node::Code(block) => Some(block),
+            _ => None,
+        })
+    }
+
+    /// Finds the first code block with the given name
+    pub fn code_block(&self, name: &str) -> Option<&CodeBlock> {
+        self.code_blocks()
+            .find(|block| block.name.as_deref() == Some(name))
+    }
+
+    /// Finds the first code block with the given name, for modification
+    pub fn code_block_mut(&mut self, name: &str) -> Option<&mut CodeBlock> {
+        self.code_blocks_mut()
+            .find(|block| block.name.as_deref() == Some(name))
+    }
+
+    /// Finds all macro invocations, as code block and name of the invoked block
+    pub fn macro_references(&self) -> impl Iterator<Item = (&CodeBlock, &str)> {
+        self.code_blocks()
+            .flat_map(|block| block.macros().map(move |name| (block, name)))
+    }
+
+    /// Finds all Markdown headings in the text blocks of this document, in ATX (`# Title`) or Setext style
+    pub fn headings(&self) -> Vec<Heading> {
+        let mut headings = vec![];
+        for (node, block) in self.nodes.iter().enumerate() {
+            let block = match block {
+                Node::Text(block) => block,
+                _ => continue,
+            };
+
+            // Previous line, if it can be the text of a Setext heading
+            let mut previous: Option<&str> = None;
+            for (line, text) in block.text.iter().enumerate() {
+                if let Some((level, title)) = atx_heading(text) {
+                    headings.push(Heading {
+                        level,
+                        text: title.to_string(),
+                        node,
+                        line,
+                        lines: 1,
+                    });
+                    previous = None;
+                } else if let (Some(level), Some(title)) = (setext_level(text), previous) {
+                    headings.push(Heading {
+                        level,
+                        text: title.trim().to_string(),
+                        node,
+                        line: line - 1,
+                        lines: 2,
+                    });
+                    previous = None;
+                } else if text.trim().is_empty() {
+                    previous = None;
+                } else {
+                    previous = Some(text);
+                }
+            }
+        }
+        headings
+    }
+
+    /// Finds all sections of this document, i.e. all headings with the content up to the next heading of the same or a higher level
+    pub fn sections(&self) -> Vec<Section> {
+        let headings = self.headings();
+        headings
+            .iter()
+            .enumerate()
+            .map(|(idx, heading)| {
+                let end = headings[idx + 1..]
+                    .iter()
+                    .find(|next| next.level <= heading.level);
+                Section {
+                    heading: heading.clone(),
+                    end_node: end.map_or(self.nodes.len(), |end| end.node),
+                    end_line: end.map_or(0, |end| end.line),
+                }
+            })
+            .collect()
+    }
+
+    /// Inserts nodes directly after the first heading with the given text.
+    /// Returns `false` if there is no such heading.
+    pub fn insert_after_heading(&mut self, heading: &str, nodes: Vec<Node>) -> bool {
+        let heading = match self
+            .headings()
+            .into_iter()
+            .find(|head| head.text == heading)
+        {
+            Some(heading) => heading,
+            None => return false,
+        };
+
+        let rest = match &mut self.nodes[heading.node] {
+            Node::Text(block) => block.text.split_off(heading.line + heading.lines),
+            _ => unreachable!("Headings are always in text blocks"),
+        };
+        let index = heading.node + 1;
+        if !rest.is_empty() {
+            self.nodes
+                .insert(index, Node::Text(TextBlock { text: rest }));
+        }
+        self.nodes.splice(index..index, nodes);
+        true
+    }
+}
+
+/// A Markdown heading in a text block of a document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heading {
+    /// Level of the heading, from 1 to 6
+    pub level: usize,
+    /// Text of the heading, without markup
+    pub text: String,
+    /// Index of the text block node containing the heading
+    pub node: usize,
+    /// Index of the heading's first line in the text block
+    pub line: usize,
+    /// Number of lines of the heading, 2 for Setext headings
+    pub lines: usize,
+}
+
+/// A section of a document, starting with a heading
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    /// The heading of the section
+    pub heading: Heading,
+    /// Index of the node containing the end of the section, or the number of nodes for the last section
+    pub end_node: usize,
+    /// Index of the line in the end node where the next section starts
+    pub end_line: usize,
+}
+
+impl Section {
+    /// Gets all the code blocks in this section, including those of sub-sections
+    pub fn code_blocks<'a>(&self, document: &'a Document) -> impl Iterator<Item = &'a CodeBlock> {
+        // The next section may start in the same text block
+        let nodes = if self.end_node > self.heading.node {
+            &document.nodes[self.heading.node + 1..self.end_node]
+        } else {
+            &[]
+        };
+        nodes.iter().filter_map(|node| match node {
+            Node::Code(block) => Some(block),
+            _ => None,
+        })
+    }
+}
+
+/// Parses an ATX heading like `## Title`, into level and text
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let content = line.trim_start_matches(' ');
+    if line.len() - content.len() > 3 {
+        return None;
+    }
+    let level = content.len() - content.trim_start_matches('#').len();
+    let text = &content[level..];
+    if level == 0 || level > 6 || !(text.is_empty() || text.starts_with([' ', '\t'])) {
+        return None;
+    }
+
+    // Optional closing sequence, like in `## Title ##`
+    let text = text.trim();
+    let stripped = text.trim_end_matches('#');
+    if stripped.is_empty() || stripped.ends_with([' ', '\t']) {
+        Some((level, stripped.trim_end()))
+    } else {
+        Some((level, text))
+    }
+}
+
+/// Level of the heading if the line is a Setext heading underline, like `===`
+fn setext_level(line: &str) -> Option<usize> {
+    let content = line.trim();
+    if line.len() - line.trim_start_matches(' ').len() > 3 || content.is_empty() {
+        None
+    } else if content.chars().all(|c| c == '=') {
+        Some(1)
+    } else if content.chars().all(|c| c == '-') {
+        Some(2)
+    } else {
+        None
+    }
 }
 
 /// A `TextBlock` is just text that will be copied verbatim into the output documentation file
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TextBlock {
     /// The source text
     pub text: Vec<String>,
@@ -102,7 +291,7 @@ pub struct Transclusion {
 }
 
 /// A `CodeBlock` is a block of code as defined by the input format.
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct CodeBlock {
     /// Source line number of the first code line
     pub line_number: usize,
@@ -141,10 +330,91 @@ impl CodeBlock {
             ..Default::default()
         }
     }
+
+    /// Names of the blocks invoked by macros in this block
+    pub fn macros(&self) -> impl Iterator<Item = &str> {
+        self.source.iter().filter_map(|line| match line {
+            Line::Macro { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Prints the code block as fenced Markdown, like in the source document
+    pub fn to_markdown(&self, settings: &ParserSettings, newline: &str) -> String {
+        let indent = &self.indent;
+        let fence = if self.is_alternative {
+            &settings.fence_sequence_alt
+        } else {
+            &settings.fence_sequence
+        };
+
+        let mut out = format!(
+            "{}{}{}{}",
+            indent,
+            fence,
+            self.language.as_deref().unwrap_or_default(),
+            newline
+        );
+        if let Some(name) = &self.name {
+            if !self.is_unnamed {
+                out.push_str(&format!(
+                    "{}{} {}{}{}{}",
+                    indent,
+                    settings.block_name_prefix,
+                    if self.is_hidden {
+                        settings.hidden_prefix.as_str()
+                    } else {
+                        ""
+                    },
+                    if self.is_file {
+                        settings.file_prefix.as_str()
+                    } else {
+                        ""
+                    },
+                    name,
+                    newline
+                ));
+            }
+        }
+        for line in &self.source {
+            match line {
+                Line::Macro {
+                    indent: line_indent,
+                    name,
+                } => {
+                    let separator = if settings.macro_start.ends_with(' ') {
+                        ""
+                    } else {
+                        " "
+                    };
+                    out.push_str(&format!(
+                        "{}{}{}{}{}{}",
+                        indent,
+                        line_indent,
+                        settings.macro_start,
+                        separator,
+                        name,
+                        settings.macro_end
+                    ));
+                }
+                Line::Source {
+                    indent: line_indent,
+                    source,
+                } => {
+                    if !line_indent.is_empty() || !source.is_empty() {
+                        out.push_str(&format!("{}{}{}", indent, line_indent, source));
+                    }
+                }
+            }
+            out.push_str(newline);
+        }
+        out.push_str(&format!("{}{}{}", indent, fence, newline));
+        out
+    }
 }
 
 /// A `Source` represents the source code on a line.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Line {
     /// A macro invocation
     Macro {
@@ -161,3 +431,154 @@ pub enum Line {
         source: String,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(lines: &[&str]) -> Node {
+        Node::Text(TextBlock {
+            text: lines.iter().map(|line| line.to_string()).collect(),
+        })
+    }
+
+    fn code(name: &str, lines: Vec<Line>) -> Node {
+        Node::Code(CodeBlock {
+            name: Some(name.to_string()),
+            language: Some("rust".to_string()),
+            source: lines,
+            ..Default::default()
+        })
+    }
+
+    fn source(source: &str) -> Line {
+        Line::Source {
+            indent: String::new(),
+            source: source.to_string(),
+        }
+    }
+
+    fn document() -> Document {
+        Document::new(
+            vec![
+                text(&["# Title", "", "Intro"]),
+                code(
+                    "Main",
+                    vec![
+                        source("fn main() {"),
+                        Line::Macro {
+                            indent: "    ".to_string(),
+                            name: "Body".to_string(),
+                        },
+                        source("}"),
+                    ],
+                ),
+                text(&["", "Details ##", "-------", "", "Text"]),
+                code("Body", vec![source("run();")]),
+                text(&["", "## Usage ##", "#hashtag"]),
+            ],
+            "\n".to_string(),
+        )
+    }
+
+    #[test]
+    fn headings_and_sections() {
+        let doc = document();
+        let headings: Vec<_> = doc
+            .headings()
+            .into_iter()
+            .map(|head| (head.level, head.text, head.node, head.line))
+            .collect();
+        assert_eq!(
+            headings,
+            vec![
+                (1, "Title".to_string(), 0, 0),
+                (2, "Details ##".to_string(), 2, 1),
+                (2, "Usage".to_string(), 4, 1),
+            ]
+        );
+
+        let sections = doc.sections();
+        assert_eq!((sections[0].end_node, sections[0].end_line), (5, 0));
+        assert_eq!((sections[1].end_node, sections[1].end_line), (4, 1));
+        let blocks = |idx: usize| -> Vec<_> {
+            sections[idx]
+                .code_blocks(&doc)
+                .map(|block| block.name.as_deref().unwrap())
+                .collect()
+        };
+        assert_eq!(blocks(0), vec!["Main", "Body"]);
+        assert_eq!(blocks(1), vec!["Body"]);
+        assert!(blocks(2).is_empty());
+    }
+
+    #[test]
+    fn sections_in_one_text_block() {
+        let doc = Document::new(
+            vec![
+                text(&["# Title", "", "## First", "", "## Second"]),
+                code("Main", vec![source("fn main() {}")]),
+            ],
+            "\n".to_string(),
+        );
+
+        let sections = doc.sections();
+        assert_eq!(sections.len(), 3);
+        assert_eq!((sections[1].end_node, sections[1].end_line), (0, 4));
+        assert_eq!(sections[1].code_blocks(&doc).count(), 0);
+        assert_eq!(sections[2].code_blocks(&doc).count(), 1);
+        assert_eq!(sections[0].code_blocks(&doc).count(), 1);
+    }
+
+    #[test]
+    fn find_and_insert() {
+        let mut doc = document();
+        assert_eq!(doc.code_block("Body").unwrap().source.len(), 1);
+        assert!(doc.code_block("Missing").is_none());
+        assert_eq!(
+            doc.macro_references()
+                .map(|(block, name)| (block.name.as_deref().unwrap(), name))
+                .collect::<Vec<_>>(),
+            vec![("Main", "Body")]
+        );
+
+        assert!(!doc.insert_after_heading("Missing", vec![text(&["x"])]));
+        assert!(doc.insert_after_heading("Details ##", vec![text(&["Inserted"])]));
+        assert_eq!(doc.nodes.len(), 7);
+        match (&doc.nodes[2], &doc.nodes[3], &doc.nodes[4]) {
+            (Node::Text(before), Node::Text(inserted), Node::Text(after)) => {
+                assert_eq!(before.text, vec!["", "Details ##", "-------"]);
+                assert_eq!(inserted.text, vec!["Inserted"]);
+                assert_eq!(after.text, vec!["", "Text"]);
+            }
+            _ => panic!("Unexpected nodes"),
+        }
+    }
+
+    #[test]
+    fn code_block_to_markdown() {
+        let doc = document();
+        let mut block = doc.code_block("Main").unwrap().clone();
+        block.is_file = true;
+
+        assert_eq!(
+            block.to_markdown(&ParserSettings::default(), "\n"),
+            "```rust\n//- file:Main\nfn main() {\n    // ==> Body.\n}\n```\n"
+        );
+    }
+
+    #[test]
+    fn indented_code_block_to_markdown() {
+        let mut block = match code("Body", vec![source("a();"), source(""), source("b();")]) {
+            Node::Code(block) => block,
+            _ => unreachable!(),
+        };
+        block.indent = "  ".to_string();
+        block.is_unnamed = true;
+
+        assert_eq!(
+            block.to_markdown(&ParserSettings::default(), "\n"),
+            "  ```rust\n  a();\n\n  b();\n  ```\n"
+        );
+    }
+}
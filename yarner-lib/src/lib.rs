@@ -5,12 +5,19 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::path::PathBuf;
 use toml::Value;
 
 mod document;
+mod persistent;
+mod settings;
+mod visit;
 
 pub use document::*;
+pub use persistent::*;
+pub use settings::*;
+pub use visit::*;
 
 /// Version of this library
 pub const YARNER_VERSION: &str = env!(
@@ -18,6 +25,10 @@ pub const YARNER_VERSION: &str = env!(
     "Environmental variable CARGO_PKG_VERSION not found"
 );
 
+/// Version of the plugin protocol implemented by this library.
+/// Version 1 is the protocol before the handshake was introduced.
+pub const PROTOCOL_VERSION: u32 = 2;
+
 /// Plugin call context
 #[derive(Debug, Serialize, Deserialize)]
 pub struct YarnerData {
@@ -25,6 +36,120 @@ pub struct YarnerData {
     pub context: Context,
     /// The documents, mapped to file paths
     pub documents: HashMap<PathBuf, Document>,
+    /// Additional files produced by the plugin, mapped to file paths relative to the output directory
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extra_files: HashMap<PathBuf, ExtraFile>,
+    /// Warnings and errors reported by the plugin
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+    /// The plugin's answer to the handshake. See [`YarnerData::set_supports`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supports: Option<Supports>,
+}
+
+impl YarnerData {
+    /// Answers the handshake with the protocol version of this library, and the capabilities the plugin opts into.
+    /// Capabilities take effect from the next call of the plugin.
+    pub fn set_supports(&mut self, capabilities: &[&str]) {
+        self.supports = Some(Supports {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: capabilities.iter().map(|cap| cap.to_string()).collect(),
+        });
+    }
+
+    /// Reports a warning, displayed by Yarner. Use [`Diagnostic::at`] to add a location.
+    pub fn warning<S: Into<String>>(&mut self, message: S) -> &mut Diagnostic {
+        self.report(DiagnosticLevel::Warning, message.into())
+    }
+
+    /// Reports an error, displayed by Yarner. Errors let the build fail in strict mode.
+    /// Use [`Diagnostic::at`] to add a location.
+    pub fn error<S: Into<String>>(&mut self, message: S) -> &mut Diagnostic {
+        self.report(DiagnosticLevel::Error, message.into())
+    }
+
+    fn report(&mut self, level: DiagnosticLevel, message: String) -> &mut Diagnostic {
+        self.diagnostics.push(Diagnostic {
+            level,
+            message,
+            path: None,
+            line: None,
+        });
+        self.diagnostics.last_mut().unwrap()
+    }
+}
+
+/// A warning or error reported by a plugin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// Severity of the diagnostic
+    pub level: DiagnosticLevel,
+    /// Description of the problem
+    pub message: String,
+    /// Path of the document the problem occurs in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Line number in the document, starting at 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+impl Diagnostic {
+    /// Sets the location of the problem, as document path and optional line number
+    pub fn at<P: Into<PathBuf>>(&mut self, path: P, line: Option<usize>) -> &mut Self {
+        self.path = Some(path.into());
+        self.line = line;
+        self
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match (&self.path, self.line) {
+            (Some(path), Some(line)) => write!(f, "{}:{}: {}", path.display(), line, self.message),
+            (Some(path), None) => write!(f, "{}: {}", path.display(), self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Severity of a diagnostic reported by a plugin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticLevel {
+    /// A warning, which is only displayed
+    Warning,
+    /// An error, which lets the build fail in strict mode
+    Error,
+}
+
+/// An additional output file produced by a plugin
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtraFile {
+    /// The output the file is written to
+    pub target: OutputTarget,
+    /// The content of the file
+    pub content: FileContent,
+}
+
+/// Output directory of an additional file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputTarget {
+    /// The documentation output directory
+    Docs,
+    /// The code output directory
+    Code,
+}
+
+/// Content of an additional output file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileContent {
+    /// A document, printed like documentation output. Only for target `docs`
+    Document(Document),
+    /// Raw text, written as is
+    Text(String),
 }
 
 /// Plugin call context
@@ -36,6 +161,37 @@ pub struct Context {
     pub name: String,
     /// Yarner version from from which the plugin is called
     pub yarner_version: String,
+    /// Version of the plugin protocol implemented by Yarner
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    /// Capabilities offered by Yarner, which the plugin can opt into with [`YarnerData::set_supports`]
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// The project's syntax of code blocks, macros and transclusions.
+    /// `None` if the plugin is called by an older version of Yarner.
+    #[serde(default)]
+    pub parser: Option<ParserSettings>,
+    /// The project's paths, including overrides from command line arguments.
+    /// `None` if the plugin is called by an older version of Yarner.
+    #[serde(default)]
+    pub paths: Option<Paths>,
+    /// The project's programming language specific settings, by language name
+    #[serde(default)]
+    pub language: HashMap<String, LanguageSettings>,
+}
+
+fn legacy_protocol_version() -> u32 {
+    1
+}
+
+/// Answer of a plugin to the handshake, in field `supports` of its output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Supports {
+    /// Version of the plugin protocol implemented by the plugin
+    pub protocol_version: u32,
+    /// Capabilities the plugin opts into
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 
 /// Read inputs from STDIN and parse into Context and Documents
@@ -0,0 +1,128 @@
+use crate::YarnerData;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+/// Command line argument Yarner starts persistent plugins with
+pub const PERSISTENT_ARG: &str = "--persistent";
+
+/// Capability a plugin opts into to be kept running across rebuilds in watch mode
+pub const PERSISTENT_CAPABILITY: &str = "persistent";
+
+/// Method of requests to process documents
+pub const PROCESS_METHOD: &str = "process";
+
+/// JSON-RPC error code for unparsable requests
+pub const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code for unknown methods
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// JSON-RPC error code for errors of the plugin
+pub const PLUGIN_ERROR: i64 = -32000;
+
+/// A JSON-RPC request, sent to persistent plugins as a single line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcRequest<P> {
+    /// JSON-RPC version, always `2.0`
+    pub jsonrpc: String,
+    /// Id of the request, repeated in the response
+    pub id: u64,
+    /// The requested method
+    pub method: String,
+    /// Parameters of the method
+    pub params: P,
+}
+
+/// A JSON-RPC response, sent by persistent plugins as a single line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcResponse<R> {
+    /// JSON-RPC version, always `2.0`
+    pub jsonrpc: String,
+    /// Id of the request, or `None` if the request could not be parsed
+    pub id: Option<u64>,
+    /// Result, in case of success
+    #[serde(default = "Option::default", skip_serializing_if = "Option::is_none")]
+    pub result: Option<R>,
+    /// Error, in case of failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+/// Error of a JSON-RPC request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    /// Error code
+    pub code: i64,
+    /// Error message
+    pub message: String,
+}
+
+/// Parameters of a `process` request.
+///
+/// `data` contains only the documents changed since the previous request.
+/// The plugin should return these, as well as any other documents it changed in response.
+/// Documents not returned keep the plugin's previous output.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProcessParams {
+    /// The context and the changed documents
+    pub data: YarnerData,
+    /// Documents removed since the previous request
+    #[serde(default)]
+    pub removed: Vec<PathBuf>,
+}
+
+/// Serves `process` requests from STDIN if the plugin is started with argument `--persistent`,
+/// until STDIN is closed. Returns `false` immediately otherwise.
+///
+/// The closure receives the parameters of each request, and returns the resulting data, or an error message.
+pub fn serve<F>(mut process: F) -> io::Result<bool>
+where
+    F: FnMut(ProcessParams) -> Result<YarnerData, String>,
+{
+    if !std::env::args().skip(1).any(|arg| arg == PERSISTENT_ARG) {
+        return Ok(false);
+    }
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<RpcRequest<serde_json::Value>>(&line) {
+            Err(err) => error_response(None, PARSE_ERROR, err.to_string()),
+            Ok(request) if request.method != PROCESS_METHOD => error_response(
+                Some(request.id),
+                METHOD_NOT_FOUND,
+                format!("Unknown method '{}'", request.method),
+            ),
+            Ok(request) => match serde_json::from_value(request.params) {
+                Err(err) => error_response(Some(request.id), PARSE_ERROR, err.to_string()),
+                Ok(params) => match process(params) {
+                    Ok(data) => RpcResponse {
+                        jsonrpc: "2.0".to_string(),
+                        id: Some(request.id),
+                        result: Some(data),
+                        error: None,
+                    },
+                    Err(message) => error_response(Some(request.id), PLUGIN_ERROR, message),
+                },
+            },
+        };
+
+        let mut out = stdout.lock();
+        writeln!(out, "{}", serde_json::to_string(&response)?)?;
+        out.flush()?;
+    }
+    Ok(true)
+}
+
+fn error_response(id: Option<u64>, code: i64, message: String) -> RpcResponse<YarnerData> {
+    RpcResponse {
+        jsonrpc: "2.0".to_string(),
+        id,
+        result: None,
+        error: Some(RpcError { code, message }),
+    }
+}
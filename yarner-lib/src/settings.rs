@@ -0,0 +1,137 @@
+//! Settings of the project, as relevant for plugins
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The syntax of code blocks, macros and transclusions in Markdown documents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParserSettings {
+    /// The sequence that identifies the start and end of a fenced code block
+    pub fence_sequence: String,
+    /// Alternative sequence that identifies the start and end of a fenced code block
+    pub fence_sequence_alt: String,
+    /// The sequence that identifies the name of a code block
+    pub block_name_prefix: String,
+    /// The sequence to identify the start of a macro invocation
+    pub macro_start: String,
+    /// The sequence to identify the end of a macro invocation
+    pub macro_end: String,
+    /// The sequence to identify the start of a transclusion
+    pub transclusion_start: String,
+    /// The sequence to identify the end of a transclusion
+    pub transclusion_end: String,
+    /// Prefix for links that should be followed during processing
+    pub link_prefix: String,
+    /// Prefix for file-specific entry points
+    pub file_prefix: String,
+    /// Name prefix for code blocks not shown in the docs
+    pub hidden_prefix: String,
+}
+
+impl Default for ParserSettings {
+    /// The settings of a project created by `yarner init`
+    fn default() -> Self {
+        Self {
+            fence_sequence: "```".to_string(),
+            fence_sequence_alt: "~~~".to_string(),
+            block_name_prefix: "//-".to_string(),
+            macro_start: "// ==>".to_string(),
+            macro_end: ".".to_string(),
+            transclusion_start: "@{{".to_string(),
+            transclusion_end: "}}".to_string(),
+            link_prefix: "@".to_string(),
+            file_prefix: "file:".to_string(),
+            hidden_prefix: "hidden:".to_string(),
+        }
+    }
+}
+
+/// Paths of the project, relative to the project root
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Paths {
+    /// Project root, relative to the directory Yarner was started from.
+    /// Plugins run in the project root.
+    pub root: Option<String>,
+    /// Code output path
+    pub code: Option<PathBuf>,
+    /// Docs output path
+    pub docs: Option<PathBuf>,
+    /// The input source file(s) as glob pattern(s)
+    pub files: Option<Vec<String>>,
+    /// File(s) to include in code output (unprocessed), as glob pattern(s)
+    pub code_files: Option<Vec<String>>,
+    /// Replacement of path components to modify code paths
+    pub code_paths: Option<Vec<String>>,
+    /// File(s) to include in docs output (unprocessed), as glob pattern(s)
+    pub doc_files: Option<Vec<String>>,
+    /// Replacement of path components to modify doc paths
+    pub doc_paths: Option<Vec<String>>,
+    /// Entrypoint block name. If not supplied, unnamed code blocks are used
+    pub entrypoint: Option<String>,
+}
+
+/// Settings for a programming language
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LanguageSettings {
+    /// Label format for blocks in code output
+    pub block_labels: Option<BlockLabels>,
+    /// Whether code lines containing only whitespace characters are printed as blank lines
+    pub clear_blank_lines: bool,
+    /// Whether code files end with a blank line
+    pub eof_newline: bool,
+    /// Whether a hidden mapping of code lines to blocks is recorded, enabling reverse mode without block labels
+    pub source_map: bool,
+    /// Whether code is printed without block labels in this run
+    pub clean_code: bool,
+}
+
+/// Format of block labels in code output
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLabels {
+    /// Start of comments in the language
+    pub comment_start: String,
+    /// Optional end of comments in the language
+    pub comment_end: Option<String>,
+    /// Start of block labels
+    pub block_start: String,
+    /// Start of next block of the same name
+    pub block_next: String,
+    /// End of block labels
+    pub block_end: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parser_settings_round_trip() {
+        let settings = ParserSettings::default();
+        let json = serde_json::to_string(&settings).unwrap();
+        let parsed: ParserSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, settings);
+    }
+
+    #[test]
+    fn partial_paths_and_languages() {
+        let paths: Paths = serde_json::from_str(r#"{"code": "code/"}"#).unwrap();
+        assert_eq!(paths.code, Some(PathBuf::from("code/")));
+        assert_eq!(paths.docs, None);
+
+        let json = r#"{
+            "block_labels": {
+                "comment_start": "//",
+                "comment_end": null,
+                "block_start": "<@",
+                "block_next": "<@>",
+                "block_end": "@>"
+            },
+            "clear_blank_lines": true,
+            "eof_newline": true,
+            "source_map": false,
+            "clean_code": false
+        }"#;
+        let language: LanguageSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(language.block_labels.unwrap().block_next, "<@>");
+        assert!(language.clear_blank_lines);
+    }
+}
@@ -0,0 +1,159 @@
+//! Traversal of the nodes of documents
+use crate::{CodeBlock, Document, Line, Node, TextBlock, Transclusion};
+
+/// Visits the nodes of a document, see [`Document::visit`].
+///
+/// All methods do nothing by default, except `visit_code`, which visits the block's lines.
+pub trait Visitor {
+    /// Visits a text block
+    fn visit_text(&mut self, _text: &TextBlock) {}
+
+    /// Visits a code block, and by default its lines
+    fn visit_code(&mut self, block: &CodeBlock) {
+        for line in &block.source {
+            self.visit_line(line);
+        }
+    }
+
+    /// Visits a line of a code block
+    fn visit_line(&mut self, _line: &Line) {}
+
+    /// Visits a transclusion
+    fn visit_transclusion(&mut self, _transclusion: &Transclusion) {}
+}
+
+/// Visits and modifies the nodes of a document, see [`Document::visit_mut`].
+///
+/// All methods do nothing by default, except `visit_code`, which visits the block's lines.
+pub trait VisitorMut {
+    /// Visits a text block
+    fn visit_text(&mut self, _text: &mut TextBlock) {}
+
+    /// Visits a code block, and by default its lines
+    fn visit_code(&mut self, block: &mut CodeBlock) {
+        for line in &mut block.source {
+            self.visit_line(line);
+        }
+    }
+
+    /// Visits a line of a code block
+    fn visit_line(&mut self, _line: &mut Line) {}
+
+    /// Visits a transclusion
+    fn visit_transclusion(&mut self, _transclusion: &mut Transclusion) {}
+}
+
+impl Document {
+    /// Visits all nodes of the document, in order
+    pub fn visit<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        for node in &self.nodes {
+            match node {
+                Node::Text(text) => visitor.visit_text(text),
+                Node::Code(block) => visitor.visit_code(block),
+                Node::Transclusion(trans) => visitor.visit_transclusion(trans),
+            }
+        }
+    }
+
+    /// Visits and possibly modifies all nodes of the document, in order
+    pub fn visit_mut<V: VisitorMut + ?Sized>(&mut self, visitor: &mut V) {
+        for node in &mut self.nodes {
+            match node {
+                Node::Text(text) => visitor.visit_text(text),
+                Node::Code(block) => visitor.visit_code(block),
+                Node::Transclusion(trans) => visitor.visit_transclusion(trans),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> Document {
+        Document::new(
+            vec![
+                Node::Text(TextBlock {
+                    text: vec!["# Title".to_string()],
+                }),
+                Node::Code(CodeBlock {
+                    name: Some("Main".to_string()),
+                    source: vec![
+                        Line::Source {
+                            indent: String::new(),
+                            source: "fn main() {".to_string(),
+                        },
+                        Line::Macro {
+                            indent: "    ".to_string(),
+                            name: "Body".to_string(),
+                        },
+                        Line::Source {
+                            indent: String::new(),
+                            source: "}".to_string(),
+                        },
+                    ],
+                    ..Default::default()
+                }),
+                Node::Transclusion(Transclusion {
+                    file: "other.md".into(),
+                    original: "@{{other.md}}".to_string(),
+                }),
+            ],
+            "\n".to_string(),
+        )
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        texts: usize,
+        lines: usize,
+        transclusions: usize,
+    }
+
+    impl Visitor for Counter {
+        fn visit_text(&mut self, _text: &TextBlock) {
+            self.texts += 1;
+        }
+
+        fn visit_line(&mut self, _line: &Line) {
+            self.lines += 1;
+        }
+
+        fn visit_transclusion(&mut self, _transclusion: &Transclusion) {
+            self.transclusions += 1;
+        }
+    }
+
+    struct RenameMacros;
+
+    impl VisitorMut for RenameMacros {
+        fn visit_line(&mut self, line: &mut Line) {
+            if let Line::Macro { name, .. } = line {
+                name.push_str(" renamed");
+            }
+        }
+    }
+
+    #[test]
+    fn visit_nodes_and_lines() {
+        let mut counter = Counter::default();
+        document().visit(&mut counter);
+        assert_eq!(
+            (counter.texts, counter.lines, counter.transclusions),
+            (1, 3, 1)
+        );
+    }
+
+    #[test]
+    fn modify_lines() {
+        let mut doc = document();
+        doc.visit_mut(&mut RenameMacros);
+        assert_eq!(
+            doc.macro_references()
+                .map(|(_, name)| name)
+                .collect::<Vec<_>>(),
+            vec!["Body renamed"]
+        );
+    }
+}